@@ -1,21 +1,36 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+mod transforms;
+
+use enigo::{Direction, Enigo, Key, Keyboard};
+use futures::future::BoxFuture;
+use futures::stream::{self, StreamExt};
 use get_selected_text::get_selected_text;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use sha2::{Digest, Sha256};
+use similar::{ChangeTag, TextDiff};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream};
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
 use tauri::{
-    api::notification::Notification, ClipboardManager, GlobalShortcutManager, Manager, SystemTray,
-    SystemTrayEvent, SystemTrayMenu,
+    ClipboardManager, GlobalShortcutManager, Manager, SystemTray, SystemTrayEvent, SystemTrayMenu,
 };
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use transforms::CaseMode;
 
 #[cfg(target_os = "macos")]
 use cocoa::appkit::{NSApp, NSApplication, NSApplicationActivationPolicyAccessory};
 
 #[derive(Serialize, Deserialize, Clone)]
 struct Settings {
+    #[serde(default)]
+    schema_version: u32, // Missing in files older than versioning, which are version 0
     shortcut: String,
     #[serde(default = "default_translate_shortcut")]
     translate_shortcut: String,
@@ -24,27 +39,618 @@ struct Settings {
     #[serde(skip_serializing_if = "Option::is_none")]
     api_key: Option<String>, // Legacy field for migration
     model: String,
-    base_url: String,
+    #[serde(default)]
+    base_urls: HashMap<String, String>, // provider -> base_url mapping
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    base_url: Option<String>, // Legacy field for migration
     prompt: String,
     provider: String,
     #[serde(default = "default_sound_enabled")]
     sound_enabled: bool,
-    #[serde(default = "default_notifications_enabled")]
-    notifications_enabled: bool,
+    #[serde(default = "default_show_result_on_copy_failure")]
+    show_result_on_copy_failure: bool, // Open the result in the preview window if copying fails
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notifications_enabled: Option<bool>, // Legacy field for migration
+    #[serde(default)]
+    notify_on_success: bool,
+    #[serde(default = "default_notify_on_error")]
+    notify_on_error: bool,
+    #[serde(default)]
+    clipboard_format: ClipboardFormat,
+    #[serde(default)]
+    show_stats_in_notification: bool,
+    #[serde(default)]
+    show_tray_counter: bool, // macOS: today's request count as the menu bar title
+    #[serde(default)]
+    replace_mode: bool,
+    #[serde(default)]
+    normalize_input: bool,
+    #[serde(default)]
+    straighten_quotes: bool, // Only applies with normalize_input
+    #[serde(default = "default_strip_preamble")]
+    strip_preamble: bool,
+    #[serde(default)]
+    few_shot_examples: Vec<(String, String)>, // (input, polished) pairs
+    #[serde(default)]
+    structured_translation: bool,
+    #[serde(default)]
+    polish_after_translate: bool, // Translating also polishes the translation
+    #[serde(default)]
+    glossary: HashMap<String, String>, // Source term -> required translation
+    #[serde(default)]
+    enforce_glossary: bool, // Replace glossary terms left untranslated in the result
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(default = "default_batch_concurrency")]
+    batch_concurrency: usize,
+    #[serde(default = "default_proofread_shortcut")]
+    proofread_shortcut: String,
+    #[serde(default = "default_proofread_prompt")]
+    proofread_prompt: String,
+    #[serde(default = "default_proofread_temperature")]
+    proofread_temperature: f32,
+    #[serde(default)]
+    watch_settings_file: bool, // Read at startup
+    #[serde(default)]
+    verify_clipboard_write: bool,
+    #[serde(default)]
+    recall_shortcut: String, // Empty means no shortcut
+    #[serde(default)]
+    clipboard_polish_shortcut: String, // Polishes the clipboard in place; empty means no shortcut
+    #[serde(default)]
+    regenerate_shortcut: String, // Creative retry of the last action; empty means no shortcut
+    #[serde(default)]
+    settings_shortcut: String, // Opens the settings window; empty means no shortcut
+    #[serde(default)]
+    retry_shortcut: String, // Briefly offered after a failed request; empty means no offer
+    #[serde(default)]
+    append_to_clipboard: bool, // Add results after the clipboard text instead of replacing it
+    #[serde(default = "default_clipboard_separator")]
+    clipboard_separator: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result_template: Option<String>, // Wraps each result, e.g. "Hi,\n\n{result}\n\nBest,\nName"
+    #[serde(default = "default_max_tokens")]
+    max_tokens: u32,
+    #[serde(default)]
+    model_max_tokens: HashMap<String, u32>, // Output limits for models not in the built-in list
+    #[serde(default)]
+    selection_capture_delay_ms: u64,
+    #[serde(default = "default_debounce_ms")]
+    debounce_ms: u64, // Repeats of a shortcut within this window are ignored; 0 disables
+    #[serde(default)]
+    shortcut_bindings: Vec<ShortcutBinding>, // Extra shortcuts on top of the built-in ones
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    daily_request_limit: Option<u32>,
+    #[serde(default)]
+    preview_before_apply: bool,
+    #[serde(default)]
+    safe_mode: bool, // Only show results; never write the clipboard or type without a click
+    #[serde(default)]
+    stream: bool, // Only affects the preview window
+    #[serde(default = "default_open_settings_on_auth_error")]
+    open_settings_on_auth_error: bool,
+    #[serde(default)]
+    auto_close_settings_on_save: bool, // Hide the settings window after a successful save
+    #[serde(default = "default_preserve_language_on_polish")]
+    preserve_language_on_polish: bool,
+    #[serde(default)]
+    verify_language_preserved: bool, // Warn when an edit comes back in another language
+    #[serde(default)]
+    retry_on_language_change: bool, // Ask again, more firmly, before warning
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    wrap_width: Option<usize>,
+    #[serde(default = "default_post_processors")]
+    post_processors: Vec<PostProcessor>, // Run in order, see apply_post_processing
+    #[serde(default = "default_commit_shortcut")]
+    commit_shortcut: String,
+    #[serde(default)]
+    code_polish_shortcut: String,
+    #[serde(default)]
+    auto_detect_code: bool, // Polish code-like selections with the code prompt
+    #[serde(default)]
+    tidy_shortcut: String,
+    #[serde(default)]
+    case_shortcut: String, // Cycles through the cases, see CaseMode::ALL
+    #[serde(default)]
+    strip_markdown_shortcut: String,
+    #[serde(default = "default_commit_prompt")]
+    commit_prompt: String,
+    #[serde(default)]
+    use_context: bool,
+    #[serde(default)]
+    context: String, // Background for polish and proofread, e.g. the surrounding paragraph
+    #[serde(default)]
+    style_guide: String, // House style layered on top of the polish prompt
+    #[serde(default)]
+    redact_patterns: Vec<String>, // Regexes masked before sending and restored in the result
+    #[serde(default)]
+    replace_method: ReplaceMethod,
+    #[serde(default)]
+    typing_delay_ms: u64, // Pause between typed characters, for apps that drop fast input
+    #[serde(default)]
+    ax_direct_write: bool, // macOS: set the focused text field's selection through AX first
+    #[serde(default = "default_tone")]
+    tone: String, // Fills the {tone} placeholder in prompts
+    #[serde(default)]
+    use_system_locale: bool, // Ask polish to follow the system locale's spelling, e.g. en-GB
+    #[serde(default)]
+    locale_override: String, // Used instead of the system locale when set
+    #[serde(default)]
+    debug_logging: bool, // Write request and response bodies to debug.log
+    #[serde(default)]
+    min_selection_length: usize, // In characters; shorter selections are ignored
+    #[serde(default = "default_confirm_large_input_chars")]
+    confirm_large_input_chars: usize, // Longer selections must be confirmed first; 0 never asks
+    #[serde(default = "default_max_result_chars")]
+    max_result_chars: usize, // Results are cut to this many characters; 0 means no limit
+    #[serde(default)]
+    target_languages: Vec<String>, // Listed in the tray's "Translate To" submenu
+    #[serde(default)]
+    cache_size: usize, // Results kept for identical requests; 0 disables the cache
+    #[serde(default = "default_cache_ttl_secs")]
+    cache_ttl_secs: u64,
+    #[serde(default)]
+    persist_cache: bool, // Keep cached results in cache.json across restarts
+    #[serde(default)]
+    fallback_providers: Vec<String>, // Tried in order when the provider is down
+    #[serde(default)]
+    provider_models: HashMap<String, String>, // provider -> last model chosen for it
+    #[serde(default)]
+    action_overrides: HashMap<String, ActionOverrides>, // action name -> model and temperature
+    #[serde(default)]
+    linux_primary_selection: bool, // Fall back to wl-paste/xclip when capture fails
+    #[serde(default)]
+    terminal_integration: bool, // Read the tmux buffer when a terminal is frontmost (macOS)
+    #[serde(default)]
+    notify_on_start: bool,
+    #[serde(default = "default_start_notification_delay_ms")]
+    start_notification_delay_ms: u64, // Skip the start notification for faster requests
+    #[serde(default)]
+    extra_headers: HashMap<String, HashMap<String, String>>, // provider -> header name -> value
+    #[serde(default)]
+    extra_body: HashMap<String, serde_json::Value>, // provider -> JSON object merged into requests
+    #[serde(default = "default_request_timeout_secs")]
+    request_timeout_secs: u64, // For providers without an entry in `timeouts`; 0 waits forever
+    #[serde(default)]
+    timeouts: HashMap<String, u64>, // provider -> seconds
+    #[serde(default = "default_max_concurrent_requests")]
+    max_concurrent_requests: usize, // Across every action, batch and comparison
+    #[serde(default)]
+    notification_strings: HashMap<String, String>, // Title key -> replacement, e.g. for another language
+    #[serde(default)]
+    notification_urgency: NotificationUrgency,
+    #[serde(default = "default_error_notification_urgency")]
+    error_notification_urgency: NotificationUrgency,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    notification_timeout_ms: Option<u64>, // None uses the system default, 0 never expires
+    #[serde(default)]
+    save_history: bool, // Append each result to history.jsonl
+    #[serde(default = "default_history_max_entry_chars")]
+    history_max_entry_chars: usize, // Longer original and result texts are truncated
+    #[serde(default)]
+    history_store_full_text: bool,
+    #[serde(default)]
+    keep_reviews: bool, // Pin every original and result to reviews.jsonl
+    #[serde(default = "default_single_instance")]
+    single_instance: bool, // Read at startup
+}
+
+const CLIPBOARD_VERIFY_DELAY: Duration = Duration::from_millis(100);
+const CLIPBOARD_VERIFY_RETRIES: u32 = 2;
+
+const SELECTION_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+const SETTINGS_WATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+// Sent by a second launch, and the running instance's answer, so a port
+// reused by another program isn't mistaken for it
+const INSTANCE_REQUEST: &str = "show-settings";
+const INSTANCE_REPLY: &str = "polish-language";
+const INSTANCE_TIMEOUT: Duration = Duration::from_millis(500);
+const INSTANCE_LOCK_ATTEMPTS: u32 = 3;
+
+// Added to the action's temperature by regenerate_creative, capped at 1.0
+const CREATIVE_TEMPERATURE_BOOST: f32 = 0.4;
+
+// A commit message is a subject line plus a short body
+const COMMIT_MESSAGE_MAX_TOKENS: u32 = 200;
+
+// Separates the improved text from the list of changes in improve_and_explain answers
+const EXPLANATION_DELIMITER: &str = "---CHANGES---";
+
+// Each example is sent with every polish request, so keep the count small
+const MAX_FEW_SHOT_EXAMPLES: usize = 5;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum TextAction {
+    Polish,
+    Translate,
+    Proofread,
+    Summarize,
+    Custom, // Uses the prompt of its shortcut binding
+    CommitMessage,
+    ImproveAndExplain, // Polishes and lists the changes, see EXPLANATION_DELIMITER
+    CodePolish,        // Improves readability of code, see looks_like_code
+    Tidy,              // Whitespace cleanup done locally, see transforms::tidy_text
+    Base64Encode,
+    Base64Decode,
+    UrlEncode,
+    UrlDecode,
+    ConvertCase,   // To the binding's `case`, see transforms::convert_case
+    StripMarkdown, // Done locally, see transforms::strip_markdown
+}
+
+impl TextAction {
+    // Actions with a dedicated shortcut setting
+    const BUILT_IN: [TextAction; 8] = [
+        TextAction::Polish,
+        TextAction::Translate,
+        TextAction::Proofread,
+        TextAction::CommitMessage,
+        TextAction::CodePolish,
+        TextAction::Tidy,
+        TextAction::ConvertCase,
+        TextAction::StripMarkdown,
+    ];
+
+    fn label(self) -> &'static str {
+        match self {
+            TextAction::Polish => "Polish",
+            TextAction::Translate => "Translate",
+            TextAction::Proofread => "Proofread",
+            TextAction::Summarize => "Summarize",
+            TextAction::Custom => "Custom",
+            TextAction::CommitMessage => "Commit Message",
+            TextAction::ImproveAndExplain => "Improve and Explain",
+            TextAction::CodePolish => "Polish Code",
+            TextAction::Tidy => "Tidy Whitespace",
+            TextAction::Base64Encode => "Base64 Encode",
+            TextAction::Base64Decode => "Base64 Decode",
+            TextAction::UrlEncode => "URL Encode",
+            TextAction::UrlDecode => "URL Decode",
+            TextAction::ConvertCase => "Convert Case",
+            TextAction::StripMarkdown => "Strip Markdown",
+        }
+    }
+
+    fn verb(self) -> &'static str {
+        match self {
+            TextAction::Polish => "polish",
+            TextAction::Translate => "translate",
+            TextAction::Proofread => "proofread",
+            TextAction::Summarize => "summarize",
+            TextAction::Custom => "process",
+            TextAction::CommitMessage => "describe",
+            TextAction::ImproveAndExplain => "improve",
+            TextAction::CodePolish => "polish",
+            TextAction::Tidy => "tidy",
+            TextAction::Base64Encode => "encode",
+            TextAction::Base64Decode => "decode",
+            TextAction::UrlEncode => "encode",
+            TextAction::UrlDecode => "decode",
+            TextAction::ConvertCase => "convert",
+            TextAction::StripMarkdown => "strip",
+        }
+    }
+
+    fn past_tense(self) -> &'static str {
+        match self {
+            TextAction::Polish => "Polished",
+            TextAction::Translate => "Translated",
+            TextAction::Proofread => "Proofread",
+            TextAction::Summarize => "Summarized",
+            TextAction::Custom => "Processed",
+            TextAction::CommitMessage => "Generated",
+            TextAction::ImproveAndExplain => "Improved",
+            TextAction::CodePolish => "Polished",
+            TextAction::Tidy => "Tidied",
+            TextAction::Base64Encode => "Encoded",
+            TextAction::Base64Decode => "Decoded",
+            TextAction::UrlEncode => "Encoded",
+            TextAction::UrlDecode => "Decoded",
+            TextAction::ConvertCase => "Converted",
+            TextAction::StripMarkdown => "Plain",
+        }
+    }
+
+    fn done_title(self) -> &'static str {
+        match self {
+            TextAction::Polish => "Text Polished",
+            TextAction::Translate => "Text Translated",
+            TextAction::Proofread => "Text Proofread",
+            TextAction::Summarize => "Text Summarized",
+            TextAction::Custom => "Text Processed",
+            TextAction::CommitMessage => "Commit Message Ready",
+            TextAction::ImproveAndExplain => "Text Improved",
+            TextAction::CodePolish => "Code Polished",
+            TextAction::Tidy => "Text Tidied",
+            TextAction::Base64Encode => "Base64 Encoded",
+            TextAction::Base64Decode => "Base64 Decoded",
+            TextAction::UrlEncode => "URL Encoded",
+            TextAction::UrlDecode => "URL Decoded",
+            TextAction::ConvertCase => "Case Converted",
+            TextAction::StripMarkdown => "Markdown Stripped",
+        }
+    }
+
+    fn failed_title(self) -> &'static str {
+        match self {
+            TextAction::Polish => "Polish Failed",
+            TextAction::Translate => "Translation Failed",
+            TextAction::Proofread => "Proofread Failed",
+            TextAction::Summarize => "Summary Failed",
+            TextAction::Custom => "Processing Failed",
+            TextAction::CommitMessage => "Commit Message Failed",
+            TextAction::ImproveAndExplain => "Improve Failed",
+            TextAction::CodePolish => "Code Polish Failed",
+            TextAction::Tidy => "Tidy Failed",
+            TextAction::Base64Encode => "Base64 Encode Failed",
+            TextAction::Base64Decode => "Base64 Decode Failed",
+            TextAction::UrlEncode => "URL Encode Failed",
+            TextAction::UrlDecode => "URL Decode Failed",
+            TextAction::ConvertCase => "Case Conversion Failed",
+            TextAction::StripMarkdown => "Strip Markdown Failed",
+        }
+    }
+
+    // Actions that revise the selection rather than produce new text, so
+    // replace mode and diff output make sense for them
+    fn edits_in_place(self) -> bool {
+        matches!(
+            self,
+            TextAction::Polish
+                | TextAction::Proofread
+                | TextAction::ImproveAndExplain
+                | TextAction::CodePolish
+                | TextAction::Tidy
+                | TextAction::ConvertCase
+                | TextAction::StripMarkdown
+        )
+    }
+
+    // Actions done on this machine, without a provider, API key or request
+    fn is_offline(self) -> bool {
+        matches!(
+            self,
+            TextAction::Tidy
+                | TextAction::Base64Encode
+                | TextAction::Base64Decode
+                | TextAction::UrlEncode
+                | TextAction::UrlDecode
+                | TextAction::ConvertCase
+                | TextAction::StripMarkdown
+        )
+    }
+
+    fn transform_locally(self, text: &str, case: Option<CaseMode>) -> Result<String, String> {
+        match self {
+            TextAction::Tidy => Ok(transforms::tidy_text(text)),
+            TextAction::Base64Encode => transforms::base64_encode(text),
+            TextAction::Base64Decode => transforms::base64_decode(text),
+            TextAction::UrlEncode => transforms::url_encode(text),
+            TextAction::UrlDecode => transforms::url_decode(text),
+            TextAction::StripMarkdown => Ok(transforms::strip_markdown(text)),
+            TextAction::ConvertCase => Ok(transforms::convert_case(
+                text,
+                case.unwrap_or(CaseMode::ALL[0]),
+            )),
+            _ => Err(format!("{} needs a provider", self.label())),
+        }
+    }
+}
+
+// Per-action replacements for the global model, the action's usual temperature
+// and the completion sound
+#[derive(Serialize, Deserialize, Clone, Default)]
+struct ActionOverrides {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sound: Option<String>, // Path to a sound file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    result_template: Option<String>, // Replaces the global one; empty turns it off
+}
+
+// A shortcut that runs an action, optionally with its own prompt and model
+#[derive(Serialize, Deserialize, Clone)]
+struct ShortcutBinding {
+    shortcut: String,
+    action: TextAction,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prompt: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    from_clipboard: bool, // Process the clipboard instead of the selection
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    case: Option<CaseMode>, // For convert_case; without it the shortcut cycles
+}
+
+impl ShortcutBinding {
+    fn label(&self) -> &'static str {
+        if self.from_clipboard && self.action == TextAction::Polish {
+            "Polish Clipboard"
+        } else {
+            self.action.label()
+        }
+    }
+}
+
+// Accelerators are case-insensitive and may contain spaces around the '+'
+fn normalize_accelerator(shortcut: &str) -> String {
+    shortcut
+        .split('+')
+        .map(|key| key.trim().to_lowercase())
+        .collect::<Vec<_>>()
+        .join("+")
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+enum ClipboardFormat {
+    #[default]
+    ResultOnly,
+    OriginalAndResult,
+    UnifiedDiff,
+}
+
+// How replace mode puts the result over the selection
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+enum ReplaceMethod {
+    #[default]
+    Type, // Simulate each character, leaving the clipboard alone
+    Paste, // Put the result on the clipboard and press Cmd/Ctrl+V
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+enum NotificationUrgency {
+    Low,
+    #[default]
+    Normal,
+    Critical,
+}
+
+// The steps that tidy up a result. Each one also needs its own setting where
+// it has one: `strip_preamble`, `wrap_width` or a result template.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+enum PostProcessor {
+    Trim,
+    StripPreamble,
+    Wrap,
+    Template,
+    UnmaskRedactions,
+}
+
+fn default_post_processors() -> Vec<PostProcessor> {
+    vec![
+        PostProcessor::UnmaskRedactions,
+        PostProcessor::StripPreamble,
+        PostProcessor::Wrap,
+        PostProcessor::Template,
+    ]
+}
+
+fn default_error_notification_urgency() -> NotificationUrgency {
+    NotificationUrgency::Critical
 }
 
 fn default_sound_enabled() -> bool {
     true
 }
 
-fn default_notifications_enabled() -> bool {
-    false
+fn default_show_result_on_copy_failure() -> bool {
+    true
+}
+
+fn default_notify_on_error() -> bool {
+    true
+}
+
+fn default_single_instance() -> bool {
+    true
+}
+
+fn default_strip_preamble() -> bool {
+    true
+}
+
+fn default_batch_concurrency() -> usize {
+    3
 }
 
 fn default_translate_shortcut() -> String {
     "CmdOrCtrl+Alt+T".to_string()
 }
 
+fn default_open_settings_on_auth_error() -> bool {
+    true
+}
+
+fn default_clipboard_separator() -> String {
+    "\n\n".to_string()
+}
+
+fn default_history_max_entry_chars() -> usize {
+    2000
+}
+
+fn default_start_notification_delay_ms() -> u64 {
+    1500
+}
+
+fn default_cache_ttl_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_tone() -> String {
+    "neutral".to_string()
+}
+
+fn default_preserve_language_on_polish() -> bool {
+    true
+}
+
+fn default_max_tokens() -> u32 {
+    1000
+}
+
+fn default_confirm_large_input_chars() -> usize {
+    20000
+}
+
+fn default_max_result_chars() -> usize {
+    100_000
+}
+
+fn default_debounce_ms() -> u64 {
+    300
+}
+
+fn default_request_timeout_secs() -> u64 {
+    60
+}
+
+fn default_max_concurrent_requests() -> usize {
+    2
+}
+
+// Maximum output tokens for known models
+fn builtin_model_max_tokens(model: &str) -> Option<u32> {
+    match model {
+        "gpt-3.5-turbo" | "gpt-4-turbo" => Some(4096),
+        "gpt-4" => Some(8192),
+        "gpt-4o" | "gpt-4o-mini" => Some(16384),
+        "o1" | "o3-mini" => Some(100000),
+        "gemini-flash-latest" | "gemini-pro-latest" => Some(8192),
+        "gemini-pro" => Some(2048),
+        "command-r-plus" | "command-r" | "command" => Some(4000),
+        _ => None,
+    }
+}
+
+fn default_proofread_shortcut() -> String {
+    "CmdOrCtrl+Alt+R".to_string()
+}
+
+fn default_proofread_prompt() -> String {
+    "Proofread the following text. Fix only spelling, grammar and punctuation errors. Do not change the wording, style or tone, and return only the corrected text:".to_string()
+}
+
+fn default_proofread_temperature() -> f32 {
+    0.1
+}
+
+fn default_commit_shortcut() -> String {
+    "CmdOrCtrl+Alt+G".to_string()
+}
+
+fn default_commit_prompt() -> String {
+    "Write a concise conventional commit message (type(scope): summary, then an optional body) for the following changes. Only return the commit message:".to_string()
+}
+
 impl Default for Settings {
     fn default() -> Self {
         Settings {
@@ -52,24 +658,165 @@ impl Default for Settings {
             translate_shortcut: default_translate_shortcut(),
             api_keys: HashMap::new(),
             api_key: None,
+            schema_version: SETTINGS_SCHEMA_VERSION,
             model: "gpt-3.5-turbo".to_string(),
-            base_url: "https://api.openai.com/v1".to_string(),
+            base_urls: HashMap::new(),
+            base_url: None,
             prompt: "Please polish and improve the following text while maintaining its original meaning and tone:".to_string(),
             provider: "openai".to_string(),
             sound_enabled: default_sound_enabled(),
-            notifications_enabled: default_notifications_enabled(),
+            show_result_on_copy_failure: default_show_result_on_copy_failure(),
+            notifications_enabled: None,
+            notify_on_success: false,
+            notify_on_error: default_notify_on_error(),
+            clipboard_format: ClipboardFormat::default(),
+            show_stats_in_notification: false,
+            show_tray_counter: false,
+            replace_mode: false,
+            normalize_input: false,
+            straighten_quotes: false,
+            strip_preamble: default_strip_preamble(),
+            few_shot_examples: Vec::new(),
+            structured_translation: false,
+            polish_after_translate: false,
+            glossary: HashMap::new(),
+            enforce_glossary: false,
+            top_p: None,
+            batch_concurrency: default_batch_concurrency(),
+            proofread_shortcut: default_proofread_shortcut(),
+            proofread_prompt: default_proofread_prompt(),
+            proofread_temperature: default_proofread_temperature(),
+            watch_settings_file: false,
+            verify_clipboard_write: false,
+            recall_shortcut: String::new(),
+            clipboard_polish_shortcut: String::new(),
+            regenerate_shortcut: String::new(),
+            settings_shortcut: String::new(),
+            retry_shortcut: String::new(),
+            append_to_clipboard: false,
+            clipboard_separator: default_clipboard_separator(),
+            result_template: None,
+            max_tokens: default_max_tokens(),
+            model_max_tokens: HashMap::new(),
+            selection_capture_delay_ms: 0,
+            debounce_ms: default_debounce_ms(),
+            shortcut_bindings: Vec::new(),
+            daily_request_limit: None,
+            preview_before_apply: false,
+            safe_mode: false,
+            stream: false,
+            open_settings_on_auth_error: default_open_settings_on_auth_error(),
+            auto_close_settings_on_save: false,
+            preserve_language_on_polish: default_preserve_language_on_polish(),
+            verify_language_preserved: false,
+            retry_on_language_change: false,
+            wrap_width: None,
+            post_processors: default_post_processors(),
+            commit_shortcut: default_commit_shortcut(),
+            code_polish_shortcut: String::new(),
+            auto_detect_code: false,
+            tidy_shortcut: String::new(),
+            case_shortcut: String::new(),
+            strip_markdown_shortcut: String::new(),
+            commit_prompt: default_commit_prompt(),
+            use_context: false,
+            context: String::new(),
+            style_guide: String::new(),
+            redact_patterns: Vec::new(),
+            replace_method: ReplaceMethod::default(),
+            typing_delay_ms: 0,
+            ax_direct_write: false,
+            tone: default_tone(),
+            use_system_locale: false,
+            locale_override: String::new(),
+            debug_logging: false,
+            min_selection_length: 0,
+            confirm_large_input_chars: default_confirm_large_input_chars(),
+            max_result_chars: default_max_result_chars(),
+            target_languages: Vec::new(),
+            cache_size: 0,
+            cache_ttl_secs: default_cache_ttl_secs(),
+            persist_cache: false,
+            fallback_providers: Vec::new(),
+            provider_models: HashMap::new(),
+            action_overrides: HashMap::new(),
+            linux_primary_selection: false,
+            terminal_integration: false,
+            notify_on_start: false,
+            start_notification_delay_ms: default_start_notification_delay_ms(),
+            extra_headers: HashMap::new(),
+            extra_body: HashMap::new(),
+            request_timeout_secs: default_request_timeout_secs(),
+            timeouts: HashMap::new(),
+            max_concurrent_requests: default_max_concurrent_requests(),
+            notification_strings: HashMap::new(),
+            notification_urgency: NotificationUrgency::default(),
+            error_notification_urgency: default_error_notification_urgency(),
+            notification_timeout_ms: None,
+            save_history: false,
+            history_max_entry_chars: default_history_max_entry_chars(),
+            history_store_full_text: false,
+            keep_reviews: false,
+            single_instance: default_single_instance(),
         }
     }
 }
 
 impl Settings {
+    // An environment variable like POLISH_OPENAI_API_KEY takes precedence over the stored key
     fn get_current_api_key(&self) -> String {
+        if let Ok(key) = std::env::var(api_key_env_var(&self.provider)) {
+            if !key.trim().is_empty() {
+                return key.trim().to_string();
+            }
+        }
+
         self.api_keys
             .get(&self.provider)
             .cloned()
             .unwrap_or_default()
     }
 
+    // Copies of these settings switched to each usable fallback provider
+    fn fallback_settings(&self) -> Vec<Settings> {
+        self.fallback_providers
+            .iter()
+            .filter(|provider| **provider != self.provider)
+            .map(|provider| Settings {
+                provider: provider.clone(),
+                model: self
+                    .provider_models
+                    .get(provider)
+                    .cloned()
+                    .unwrap_or_else(|| provider_registry().default_model(provider).to_string()),
+                ..self.clone()
+            })
+            .filter(|fallback| !fallback.get_current_api_key().is_empty())
+            .collect()
+    }
+
+    fn get_current_base_url(&self) -> String {
+        self.base_urls
+            .get(&self.provider)
+            .filter(|base_url| !base_url.is_empty())
+            .cloned()
+            .unwrap_or_else(|| {
+                provider_registry()
+                    .get(&self.provider)
+                    .map_or("", |entry| entry.default_base_url)
+                    .to_string()
+            })
+    }
+
+    fn set_base_url(&mut self, provider: &str, base_url: &str) {
+        if base_url.is_empty() {
+            self.base_urls.remove(provider);
+        } else {
+            self.base_urls
+                .insert(provider.to_string(), base_url.to_string());
+        }
+    }
+
     fn set_api_key(&mut self, provider: &str, api_key: &str) {
         if api_key.is_empty() {
             self.api_keys.remove(provider);
@@ -79,7 +826,216 @@ impl Settings {
         }
     }
 
-    // Migration helper to convert old single api_key to provider-based keys
+    // The current provider's timeout, else the global one. None means no limit.
+    fn request_timeout(&self) -> Option<Duration> {
+        let secs = self
+            .timeouts
+            .get(&self.provider)
+            .copied()
+            .unwrap_or(self.request_timeout_secs);
+        (secs > 0).then(|| Duration::from_secs(secs))
+    }
+
+    // The configured max_tokens, clamped to what the model can produce
+    fn effective_max_tokens(&self) -> u32 {
+        let limit = self
+            .model_max_tokens
+            .get(&self.model)
+            .copied()
+            .or_else(|| builtin_model_max_tokens(&self.model));
+        match limit {
+            Some(limit) if self.max_tokens > limit => limit,
+            _ => self.max_tokens,
+        }
+    }
+
+    // Keyed by the action's name in settings.json, e.g. "translate"
+    fn overrides_for(&self, action: TextAction) -> Option<&ActionOverrides> {
+        let key = serde_json::to_value(action).ok()?;
+        self.action_overrides.get(key.as_str()?)
+    }
+
+    // `key`'s title from `notification_strings`, else `default`
+    fn notification_title<'a>(&'a self, key: &str, default: &'a str) -> &'a str {
+        self.notification_strings
+            .get(key)
+            .map(String::as_str)
+            .filter(|title| !title.trim().is_empty())
+            .unwrap_or(default)
+    }
+
+    // A title from `NOTIFICATION_TITLES`, unless replaced in `notification_strings`
+    fn title<'a>(&'a self, key: &'a str) -> &'a str {
+        let default = NOTIFICATION_TITLES
+            .iter()
+            .find(|(name, _)| *name == key)
+            .map_or(key, |(_, title)| title);
+        self.notification_title(key, default)
+    }
+
+    // Per-action titles are keyed by the action's name and `event`, e.g. "translate_done"
+    fn action_title<'a>(&'a self, action: TextAction, event: &str, default: &'a str) -> &'a str {
+        let name = serde_json::to_value(action).ok();
+        match name.as_ref().and_then(|name| name.as_str()) {
+            Some(name) => self.notification_title(&format!("{}_{}", name, event), default),
+            None => default,
+        }
+    }
+
+    // The action's own sound file, if one is set
+    fn action_sound(&self, action: TextAction) -> Option<&str> {
+        self.overrides_for(action)
+            .and_then(|overrides| overrides.sound.as_deref())
+            .filter(|sound| !sound.trim().is_empty())
+    }
+
+    // Puts `result` into the action's `result_template`, else the global one.
+    // A template without a {result} placeholder is ignored so the result
+    // isn't lost.
+    fn apply_result_template(&self, action: TextAction, result: String) -> String {
+        let template = self
+            .overrides_for(action)
+            .and_then(|overrides| overrides.result_template.as_deref())
+            .or(self.result_template.as_deref())
+            .filter(|template| !template.trim().is_empty());
+        match template {
+            Some(template) if template.contains("{result}") => {
+                template.replace("{result}", &result)
+            }
+            Some(_) => {
+                eprintln!("Ignoring result_template without a {{result}} placeholder");
+                result
+            }
+            None => result,
+        }
+    }
+
+    // Switches to the action's model override, if any
+    fn apply_action_model(&mut self, action: TextAction) {
+        if let Some(model) = self
+            .overrides_for(action)
+            .and_then(|overrides| overrides.model.clone())
+        {
+            self.model = model;
+        }
+    }
+
+    fn shortcut_for(&self, action: TextAction) -> &str {
+        match action {
+            TextAction::Polish => &self.shortcut,
+            TextAction::Translate => &self.translate_shortcut,
+            TextAction::Proofread => &self.proofread_shortcut,
+            TextAction::CommitMessage => &self.commit_shortcut,
+            TextAction::CodePolish => &self.code_polish_shortcut,
+            TextAction::Tidy => &self.tidy_shortcut,
+            TextAction::ConvertCase => &self.case_shortcut,
+            TextAction::StripMarkdown => &self.strip_markdown_shortcut,
+            TextAction::Summarize
+            | TextAction::Custom
+            | TextAction::ImproveAndExplain
+            | TextAction::Base64Encode
+            | TextAction::Base64Decode
+            | TextAction::UrlEncode
+            | TextAction::UrlDecode => "",
+        }
+    }
+
+    // The built-in shortcuts followed by the user-defined bindings, skipping
+    // unset shortcuts
+    fn all_shortcut_bindings(&self) -> Vec<ShortcutBinding> {
+        TextAction::BUILT_IN
+            .into_iter()
+            .map(|action| ShortcutBinding {
+                shortcut: self.shortcut_for(action).to_string(),
+                action,
+                prompt: None,
+                model: None,
+                from_clipboard: false,
+                case: None,
+            })
+            .chain(Some(ShortcutBinding {
+                shortcut: self.clipboard_polish_shortcut.clone(),
+                action: TextAction::Polish,
+                prompt: None,
+                model: None,
+                from_clipboard: true,
+                case: None,
+            }))
+            .chain(self.shortcut_bindings.iter().cloned())
+            .filter(|binding| !binding.shortcut.is_empty())
+            .collect()
+    }
+
+    fn validate_shortcuts(&self) -> Result<(), String> {
+        let mut seen = HashSet::new();
+        let shortcuts = self
+            .all_shortcut_bindings()
+            .into_iter()
+            .map(|binding| binding.shortcut)
+            .chain([
+                self.recall_shortcut.clone(),
+                self.regenerate_shortcut.clone(),
+                self.settings_shortcut.clone(),
+                self.retry_shortcut.clone(),
+            ])
+            .filter(|shortcut| !shortcut.is_empty());
+        for shortcut in shortcuts {
+            if !seen.insert(normalize_accelerator(&shortcut)) {
+                return Err(format!("Shortcut {} is assigned more than once", shortcut));
+            }
+        }
+
+        for binding in &self.shortcut_bindings {
+            if binding.action == TextAction::Custom && binding.prompt.is_none() {
+                return Err(format!(
+                    "Custom shortcut {} needs a prompt",
+                    binding.shortcut
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn few_shot_examples(&self) -> &[(String, String)] {
+        let count = self.few_shot_examples.len().min(MAX_FEW_SHOT_EXAMPLES);
+        &self.few_shot_examples[..count]
+    }
+
+    // Upgrades settings read from an older file one version at a time
+    fn migrate(&mut self) {
+        for step in SETTINGS_MIGRATIONS
+            .iter()
+            .skip(self.schema_version as usize)
+        {
+            step(self);
+        }
+        self.schema_version = SETTINGS_SCHEMA_VERSION;
+    }
+
+    // Version 1 to 2: one base_url for every provider became per-provider base_urls
+    fn migrate_legacy_base_url(&mut self) {
+        if let Some(legacy_url) = self.base_url.take() {
+            // A default URL left over from another provider is the bug this
+            // map fixes, so don't carry it over
+            let leftover = provider_registry()
+                .entries()
+                .any(|entry| entry.id != self.provider && legacy_url == entry.default_base_url);
+            if !leftover && !self.base_urls.contains_key(&self.provider) {
+                let provider = self.provider.clone();
+                self.set_base_url(&provider, &legacy_url);
+            }
+        }
+    }
+
+    // Version 2 to 3: the old single switch covered both kinds of notification
+    fn migrate_legacy_notifications(&mut self) {
+        if let Some(enabled) = self.notifications_enabled.take() {
+            self.notify_on_success = enabled;
+            self.notify_on_error = enabled;
+        }
+    }
+
+    // Version 0 to 1: convert the old single api_key to provider-based keys
     fn migrate_legacy_api_key(&mut self) {
         if let Some(legacy_key) = &self.api_key {
             if !legacy_key.is_empty() && !self.api_keys.contains_key(&self.provider) {
@@ -91,12 +1047,38 @@ impl Settings {
     }
 }
 
+// Migration steps in order; the step at index N upgrades version N to N + 1.
+// Add a step and bump the version for every change that needs one.
+const SETTINGS_MIGRATIONS: [fn(&mut Settings); 3] = [
+    Settings::migrate_legacy_api_key,
+    Settings::migrate_legacy_base_url,
+    Settings::migrate_legacy_notifications,
+];
+
+const SETTINGS_SCHEMA_VERSION: u32 = SETTINGS_MIGRATIONS.len() as u32;
+
 #[derive(Serialize, Deserialize)]
 struct OpenAIRequest {
     model: String,
     messages: Vec<OpenAIMessage>,
-    max_tokens: u32,
-    temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<u32>, // Replaces max_tokens for reasoning models
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<OpenAIResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpenAIResponseFormat {
+    #[serde(rename = "type")]
+    format_type: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -108,11 +1090,42 @@ struct OpenAIMessage {
 #[derive(Serialize, Deserialize)]
 struct OpenAIResponse {
     choices: Vec<OpenAIChoice>,
+    #[serde(default)]
+    usage: Option<OpenAIUsage>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpenAIUsage {
+    #[serde(default)]
+    prompt_tokens: u32,
+    #[serde(default)]
+    completion_tokens: u32,
 }
 
 #[derive(Serialize, Deserialize)]
 struct OpenAIChoice {
     message: OpenAIMessage,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+// One server-sent event of a streamed chat completion
+#[derive(Serialize, Deserialize)]
+struct OpenAIStreamChunk {
+    choices: Vec<OpenAIStreamChoice>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpenAIStreamChoice {
+    delta: OpenAIDelta,
+    #[serde(default)]
+    finish_reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OpenAIDelta {
+    #[serde(default)]
+    content: Option<String>,
 }
 
 // Gemini API structures
@@ -138,392 +1151,5150 @@ struct GeminiGenerationConfig {
     temperature: f32,
     #[serde(rename = "maxOutputTokens")]
     max_output_tokens: u32,
+    #[serde(rename = "topP", skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
 }
 
 #[derive(Serialize, Deserialize)]
 struct GeminiResponse {
     candidates: Vec<GeminiCandidate>,
+    #[serde(rename = "usageMetadata", default)]
+    usage_metadata: Option<GeminiUsage>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GeminiUsage {
+    #[serde(rename = "promptTokenCount", default)]
+    prompt_token_count: u32,
+    #[serde(rename = "candidatesTokenCount", default)]
+    candidates_token_count: u32,
 }
 
 #[derive(Serialize, Deserialize)]
 struct GeminiCandidate {
     content: GeminiContent,
+    #[serde(rename = "finishReason", default)]
+    finish_reason: Option<String>,
 }
 
-fn get_settings_path() -> PathBuf {
-    let mut path = dirs::config_dir().unwrap_or_else(|| PathBuf::from("."));
-    path.push("polish-language");
-    fs::create_dir_all(&path).ok();
-    path.push("settings.json");
-    path
+// Cohere API structures
+#[derive(Serialize, Deserialize)]
+struct CohereRequest {
+    model: String,
+    message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    chat_history: Vec<CohereMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preamble: Option<String>,
+    max_tokens: u32,
+    temperature: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    p: Option<f32>,
 }
 
-fn play_completion_sound() {
-    #[cfg(target_os = "macos")]
-    {
-        use std::process::Command;
-        let _ = Command::new("afplay")
-            .arg("/System/Library/Sounds/Glass.aiff")
-            .spawn();
-    }
+#[derive(Serialize, Deserialize)]
+struct CohereMessage {
+    role: String,
+    message: String,
 }
 
-fn show_notification(app_handle: &tauri::AppHandle, title: &str, body: &str, settings: &Settings) {
-    if settings.notifications_enabled {
-        let _ = Notification::new(&app_handle.config().tauri.bundle.identifier)
-            .title(title)
-            .body(body)
-            .show();
-    }
+#[derive(Serialize, Deserialize)]
+struct CohereResponse {
+    text: String,
+    #[serde(default)]
+    finish_reason: Option<String>,
+    #[serde(default)]
+    meta: Option<CohereMeta>,
 }
 
-fn update_tray_icon_processing(app_handle: &tauri::AppHandle, processing: bool) {
-    let tray = app_handle.tray_handle();
-    // On macOS, we can change the tray icon to indicate processing
-    // For now, we'll just use the tooltip to show status
-    let tooltip = if processing {
-        "Polish Language - Processing..."
-    } else {
-        "Polish Language"
-    };
-    let _ = tray.set_tooltip(tooltip);
+#[derive(Serialize, Deserialize)]
+struct CohereMeta {
+    #[serde(default)]
+    billed_units: Option<CohereBilledUnits>,
 }
 
-#[tauri::command]
-fn save_settings(mut settings: Settings) -> Result<(), String> {
-    // Ensure legacy field is cleared
-    settings.api_key = None;
+#[derive(Serialize, Deserialize)]
+struct CohereBilledUnits {
+    #[serde(default)]
+    input_tokens: u32,
+    #[serde(default)]
+    output_tokens: u32,
+}
 
-    let settings_path = get_settings_path();
-    let json = serde_json::to_string_pretty(&settings)
-        .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+// Token counts reported by the provider, when it reports them
+#[derive(Clone, Copy, Serialize)]
+struct TokenUsage {
+    input_tokens: u32,
+    output_tokens: u32,
+}
 
-    fs::write(settings_path, json).map_err(|e| format!("Failed to write settings: {}", e))?;
+// Text returned by a provider, plus whether it stopped because of the token limit
+struct LlmOutput {
+    text: String,
+    truncated: bool,
+    translation_details: Option<TranslationDetails>,
+    cached: bool,                      // Served from the result cache without a request
+    fallback_provider: Option<String>, // Set when the primary provider failed
+    usage: Option<TokenUsage>,
+    reduced_max_tokens: Option<u32>, // Set when a context-length error forced a smaller limit
+}
 
-    Ok(())
+impl LlmOutput {
+    // The result of an offline action
+    fn local(text: String) -> Self {
+        LlmOutput {
+            text,
+            truncated: false,
+            translation_details: None,
+            cached: false,
+            fallback_provider: None,
+            usage: None,
+            reduced_max_tokens: None,
+        }
+    }
 }
 
-#[tauri::command]
-fn get_api_key_for_provider(provider: String) -> String {
-    let settings = load_settings();
-    settings
-        .api_keys
-        .get(&provider)
-        .cloned()
-        .unwrap_or_default()
+// Shape requested from the model when `structured_translation` is on
+#[derive(Deserialize)]
+struct StructuredTranslation {
+    translation: String,
+    #[serde(flatten)]
+    details: TranslationDetails,
 }
 
-#[tauri::command]
-fn save_api_key_for_provider(provider: String, api_key: String) -> Result<(), String> {
-    let mut settings = load_settings();
-    settings.set_api_key(&provider, &api_key);
-    save_settings(settings)
+#[derive(Deserialize)]
+struct TranslationDetails {
+    #[serde(default)]
+    detected_language: Option<String>,
+    #[serde(default)]
+    confidence: Option<f32>,
 }
 
-#[tauri::command]
-fn load_settings() -> Settings {
-    let settings_path = get_settings_path();
+// Structured translations below this confidence are flagged for review
+const LOW_CONFIDENCE_THRESHOLD: f32 = 0.6;
 
-    if let Ok(content) = fs::read_to_string(settings_path) {
-        let mut settings: Settings = serde_json::from_str(&content).unwrap_or_default();
-        settings.migrate_legacy_api_key();
-        settings
-    } else {
-        Settings::default()
-    }
-}
+// Language detection on shorter text is too unreliable to warn about
+const MIN_LANGUAGE_CHECK_CHARS: usize = 40;
+// Entries past this are left out of the translate prompt
+const MAX_GLOSSARY_CHARS: usize = 2000;
+
+const NO_CHOICES_ERROR: &str = "API response contained no choices";
+const EMPTY_CONTENT_ERROR: &str = "API response contained empty content";
 
-async fn polish_text_with_llm(text: &str, settings: &Settings) -> Result<String, String> {
-    let client = reqwest::Client::new();
+// An empty answer is usually transient, so it is retried this many times
+const EMPTY_RESPONSE_RETRIES: u32 = 1;
 
-    match settings.provider.as_str() {
-        "gemini" => polish_text_with_gemini(text, settings, &client).await,
-        _ => polish_text_with_openai(text, settings, &client).await,
+// Error bodies can be whole HTML pages, so only the start is kept
+const MAX_ERROR_BODY_CHARS: usize = 500;
+
+// Shared by every provider so a missing or blank answer is reported the same way
+fn parse_llm_text(text: Option<&str>, truncated: bool) -> Result<LlmOutput, String> {
+    let text = text.ok_or_else(|| NO_CHOICES_ERROR.to_string())?.trim();
+    if text.is_empty() {
+        return Err(EMPTY_CONTENT_ERROR.to_string());
     }
+    Ok(LlmOutput {
+        text: text.to_string(),
+        truncated,
+        translation_details: None,
+        cached: false,
+        fallback_provider: None,
+        usage: None,
+        reduced_max_tokens: None,
+    })
 }
 
-async fn translate_text_with_llm(text: &str, settings: &Settings) -> Result<String, String> {
-    let client = reqwest::Client::new();
+fn is_empty_response_error(error: &str) -> bool {
+    error == NO_CHOICES_ERROR || error == EMPTY_CONTENT_ERROR
+}
 
-    let translate_prompt = "Translate the following text to English. If the text is already in English, keep it as is. Only return the translated text without any additional explanation:";
+// How OpenAI, Gemini and Cohere word "input plus output is too long for the model"
+const CONTEXT_LENGTH_PATTERNS: [&str; 5] = [
+    "context_length_exceeded",
+    "maximum context length",
+    "exceeds the maximum number of tokens",
+    "too many tokens",
+    "context window",
+];
 
-    match settings.provider.as_str() {
-        "gemini" => translate_text_with_gemini(text, translate_prompt, settings, &client).await,
-        _ => translate_text_with_openai(text, translate_prompt, settings, &client).await,
-    }
+fn is_context_length_error(error: &str) -> bool {
+    let error = error.to_lowercase();
+    (error.contains("status: 400") || error.contains("status: 413"))
+        && CONTEXT_LENGTH_PATTERNS
+            .iter()
+            .any(|pattern| error.contains(pattern))
 }
 
-async fn polish_text_with_openai(
-    text: &str,
-    settings: &Settings,
-    client: &reqwest::Client,
-) -> Result<String, String> {
-    let request = OpenAIRequest {
-        model: settings.model.clone(),
-        messages: vec![
-            OpenAIMessage {
-                role: "system".to_string(),
-                content: settings.prompt.clone(),
-            },
-            OpenAIMessage {
-                role: "user".to_string(),
-                content: text.to_string(),
-            },
-        ],
-        max_tokens: 1000,
-        temperature: 0.3,
-    };
-
-    let response = client
-        .post(format!("{}/chat/completions", settings.base_url))
-        .header(
-            "Authorization",
-            format!("Bearer {}", settings.get_current_api_key()),
-        )
-        .header("Content-Type", "application/json")
-        .json(&request)
-        .send()
-        .await
-        .map_err(|e| format!("Request failed: {}", e))?;
+fn api_key_env_var(provider: &str) -> String {
+    let provider: String = provider
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    format!("POLISH_{}_API_KEY", provider)
+}
 
-    if !response.status().is_success() {
-        return Err(format!(
-            "API request failed with status: {}",
-            response.status()
-        ));
-    }
+fn get_settings_path() -> PathBuf {
+    let mut path = dirs::config_dir().unwrap_or_else(|| PathBuf::from("."));
+    path.push("polish-language");
+    fs::create_dir_all(&path).ok();
+    path.push("settings.json");
+    path
+}
 
-    let openai_response: OpenAIResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
+// Reveals the directory holding settings.json and usage.json in the file browser
+#[tauri::command]
+fn open_config_dir() -> Result<(), String> {
+    let path = get_settings_path();
+    let dir = path.parent().unwrap_or(&path);
+    open::that(dir).map_err(|e| format!("Failed to open {}: {}", dir.display(), e))
+}
 
-    openai_response
-        .choices
-        .first()
-        .map(|choice| choice.message.content.trim().to_string())
-        .ok_or_else(|| "No response from API".to_string())
+// Requests made today, stored next to the settings
+#[derive(Serialize, Deserialize, Default)]
+struct Usage {
+    date: String, // Local date, YYYY-MM-DD
+    count: u32,
 }
 
-async fn polish_text_with_gemini(
-    text: &str,
-    settings: &Settings,
-    client: &reqwest::Client,
-) -> Result<String, String> {
-    let combined_prompt = format!("{}\n\n{}", settings.prompt, text);
+// Serializes read-modify-write of usage.json between concurrent requests
+static USAGE_LOCK: Mutex<()> = Mutex::new(());
 
-    let request = GeminiRequest {
-        contents: vec![GeminiContent {
-            parts: vec![GeminiPart {
-                text: combined_prompt,
-            }],
-        }],
-        generation_config: GeminiGenerationConfig {
-            temperature: 0.3,
-            max_output_tokens: 1000,
-        },
-    };
+fn get_usage_path() -> PathBuf {
+    get_settings_path().with_file_name("usage.json")
+}
+
+const REDACTED: &str = "[REDACTED]";
 
+// Hides the API key wherever it appears, including Gemini URLs and
+// Authorization headers. Anything showing request details goes through this.
+fn redact_secrets(text: &str, settings: &Settings) -> String {
     let api_key = settings.get_current_api_key();
-    let url = if settings.base_url.contains("generateContent") {
-        format!("{}?key={}", settings.base_url, api_key)
+    if api_key.is_empty() {
+        text.to_string()
     } else {
-        format!(
-            "{}/v1beta/models/{}:generateContent?key={}",
-            settings.base_url, settings.model, api_key
-        )
-    };
+        text.replace(&api_key, REDACTED)
+    }
+}
 
-    let response = client
-        .post(&url)
-        .header("Content-Type", "application/json")
-        .json(&request)
-        .send()
-        .await
-        .map_err(|e| format!("Request failed: {}", e))?;
+// redact_secrets for the provider and its fallbacks, since a failed request
+// may have been a fallback's
+fn redact_error(error: &str, settings: &Settings) -> String {
+    settings
+        .fallback_settings()
+        .iter()
+        .fold(redact_secrets(error, settings), |error, fallback| {
+            redact_secrets(&error, fallback)
+        })
+}
 
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response.text().await.unwrap_or_default();
-        return Err(format!(
-            "API request failed with status: {} - {}",
-            status, error_text
-        ));
-    }
+fn get_debug_log_path() -> PathBuf {
+    get_settings_path().with_file_name("debug.log")
+}
 
-    let gemini_response: GeminiResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
+// Appends `content` to debug.log when debug logging is on
+fn debug_log(settings: &Settings, label: &str, content: &str) {
+    if !settings.debug_logging {
+        return;
+    }
+    let entry = redact_secrets(
+        &format!(
+            "[{}] {}\n{}\n\n",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+            label,
+            content
+        ),
+        settings,
+    );
+    let written = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(get_debug_log_path())
+        .and_then(|mut file| file.write_all(entry.as_bytes()));
+    if let Err(e) = written {
+        eprintln!("Failed to write debug log: {}", e);
+    }
+}
 
-    gemini_response
-        .candidates
-        .first()
-        .and_then(|candidate| candidate.content.parts.first())
-        .map(|part| part.text.trim().to_string())
-        .ok_or_else(|| "No response from API".to_string())
+fn log_request(settings: &Settings, url: &str, request: &impl Serialize) {
+    if settings.debug_logging {
+        let body = serde_json::to_string_pretty(request).unwrap_or_default();
+        debug_log(settings, &format!("Request to {}", url), &body);
+    }
 }
 
-async fn translate_text_with_openai(
-    text: &str,
-    translate_prompt: &str,
+// Reads the whole body so it can be logged before parsing
+async fn read_response<T: serde::de::DeserializeOwned>(
+    response: reqwest::Response,
     settings: &Settings,
-    client: &reqwest::Client,
-) -> Result<String, String> {
-    let request = OpenAIRequest {
-        model: settings.model.clone(),
-        messages: vec![
-            OpenAIMessage {
-                role: "system".to_string(),
-                content: translate_prompt.to_string(),
-            },
-            OpenAIMessage {
-                role: "user".to_string(),
-                content: text.to_string(),
-            },
-        ],
-        max_tokens: 1000,
-        temperature: 0.1, // Lower temperature for more consistent translations
-    };
-
-    let response = client
-        .post(format!("{}/chat/completions", settings.base_url))
-        .header(
-            "Authorization",
-            format!("Bearer {}", settings.get_current_api_key()),
-        )
-        .header("Content-Type", "application/json")
-        .json(&request)
-        .send()
+) -> Result<T, String> {
+    let body = response
+        .text()
         .await
-        .map_err(|e| format!("Request failed: {}", e))?;
+        .map_err(|e| format!("Failed to read response: {}", e))?;
+    debug_log(settings, "Response", &body);
+    serde_json::from_str(&body).map_err(|e| format!("Failed to parse response: {}", e))
+}
 
-    if !response.status().is_success() {
-        return Err(format!(
-            "API request failed with status: {}",
-            response.status()
-        ));
+fn load_usage() -> Usage {
+    let today = chrono::Local::now().format("%Y-%m-%d").to_string();
+    let usage = fs::read_to_string(get_usage_path())
+        .ok()
+        .and_then(|content| serde_json::from_str::<Usage>(&content).ok())
+        .unwrap_or_default();
+
+    // The count starts over at local midnight
+    if usage.date == today {
+        usage
+    } else {
+        Usage {
+            date: today,
+            count: 0,
+        }
     }
+}
 
-    let openai_response: OpenAIResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
+// Counts a request against `daily_request_limit`, refusing once it is reached
+fn record_request(settings: &Settings) -> Result<(), String> {
+    let _guard = USAGE_LOCK.lock().unwrap();
+    let mut usage = load_usage();
+    if let Some(limit) = settings.daily_request_limit {
+        if usage.count >= limit {
+            return Err(format!(
+                "Daily limit of {} requests reached. It resets at midnight.",
+                limit
+            ));
+        }
+    }
 
-    openai_response
-        .choices
-        .first()
-        .map(|choice| choice.message.content.trim().to_string())
-        .ok_or_else(|| "No response from API".to_string())
+    usage.count += 1;
+    let json = serde_json::to_string_pretty(&usage)
+        .map_err(|e| format!("Failed to serialize usage: {}", e))?;
+    fs::write(get_usage_path(), json).map_err(|e| format!("Failed to write usage: {}", e))
 }
 
-async fn translate_text_with_gemini(
-    text: &str,
-    translate_prompt: &str,
-    settings: &Settings,
-    client: &reqwest::Client,
-) -> Result<String, String> {
-    let combined_prompt = format!("{}\n\n{}", translate_prompt, text);
-
-    let request = GeminiRequest {
-        contents: vec![GeminiContent {
-            parts: vec![GeminiPart {
-                text: combined_prompt,
-            }],
-        }],
-        generation_config: GeminiGenerationConfig {
-            temperature: 0.1, // Lower temperature for more consistent translations
-            max_output_tokens: 1000,
-        },
-    };
+#[tauri::command]
+fn get_today_count() -> u32 {
+    load_usage().count
+}
 
-    let api_key = settings.get_current_api_key();
-    let url = if settings.base_url.contains("generateContent") {
-        format!("{}?key={}", settings.base_url, api_key)
-    } else {
-        format!(
-            "{}/v1beta/models/{}:generateContent?key={}",
-            settings.base_url, settings.model, api_key
-        )
-    };
+// One line of history.jsonl
+#[derive(Serialize)]
+struct HistoryEntry<'a> {
+    timestamp: String,
+    action: TextAction,
+    provider: &'a str,
+    model: &'a str,
+    original_chars: usize, // Full lengths, even when the text is truncated
+    result_chars: usize,
+    original: String,
+    result: String,
+}
 
-    let response = client
-        .post(&url)
-        .header("Content-Type", "application/json")
-        .json(&request)
-        .send()
-        .await
-        .map_err(|e| format!("Request failed: {}", e))?;
+fn get_history_path() -> PathBuf {
+    get_settings_path().with_file_name("history.jsonl")
+}
 
-    if !response.status().is_success() {
-        let status = response.status();
-        let error_text = response.text().await.unwrap_or_default();
-        return Err(format!(
-            "API request failed with status: {} - {}",
-            status, error_text
-        ));
+// Shortens `text` to `history_max_entry_chars` unless full text is kept
+fn history_text(text: &str, settings: &Settings) -> String {
+    let max_chars = settings.history_max_entry_chars;
+    if settings.history_store_full_text || text.chars().count() <= max_chars {
+        return text.to_string();
     }
+    format!(
+        "{}… [truncated]",
+        text.chars().take(max_chars).collect::<String>()
+    )
+}
 
-    let gemini_response: GeminiResponse = response
-        .json()
-        .await
-        .map_err(|e| format!("Failed to parse response: {}", e))?;
+fn append_history(
+    settings: &Settings,
+    action: TextAction,
+    original: &str,
+    result: &str,
+) -> Result<(), String> {
+    let entry = HistoryEntry {
+        timestamp: chrono::Local::now().to_rfc3339(),
+        action,
+        provider: &settings.provider,
+        model: &settings.model,
+        original_chars: original.chars().count(),
+        result_chars: result.chars().count(),
+        original: history_text(original, settings),
+        result: history_text(result, settings),
+    };
+    let line = serde_json::to_string(&entry)
+        .map_err(|e| format!("Failed to serialize history entry: {}", e))?;
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(get_history_path())
+        .and_then(|mut file| writeln!(file, "{}", line))
+        .map_err(|e| format!("Failed to write history: {}", e))
+}
 
-    gemini_response
-        .candidates
-        .first()
-        .and_then(|candidate| candidate.content.parts.first())
-        .map(|part| part.text.trim().to_string())
-        .ok_or_else(|| "No response from API".to_string())
+// One line of reviews.jsonl. Unlike history entries, reviews always keep the
+// full texts and aren't affected by the history settings, so every change the
+// app made can be audited later.
+#[derive(Serialize, Deserialize)]
+struct PinnedReview {
+    timestamp: String,
+    action: TextAction,
+    provider: String,
+    model: String,
+    original: String,
+    result: String,
 }
 
-#[tokio::main]
-async fn main() {
-    let tray_menu = SystemTrayMenu::new()
-        .add_item(tauri::CustomMenuItem::new(
-            "settings".to_string(),
-            "Settings",
+fn get_reviews_path() -> PathBuf {
+    get_settings_path().with_file_name("reviews.jsonl")
+}
+
+fn append_review(
+    settings: &Settings,
+    action: TextAction,
+    original: &str,
+    result: &str,
+) -> Result<(), String> {
+    let review = PinnedReview {
+        timestamp: chrono::Local::now().to_rfc3339(),
+        action,
+        provider: settings.provider.clone(),
+        model: settings.model.clone(),
+        original: original.to_string(),
+        result: result.to_string(),
+    };
+    let line =
+        serde_json::to_string(&review).map_err(|e| format!("Failed to serialize review: {}", e))?;
+    fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(get_reviews_path())
+        .and_then(|mut file| writeln!(file, "{}", line))
+        .map_err(|e| format!("Failed to write review: {}", e))
+}
+
+// Records an applied result in the history and, with `keep_reviews`, as a review
+fn record_result(settings: &Settings, action: TextAction, original: &str, result: &str) {
+    if settings.save_history {
+        if let Err(e) = append_history(settings, action, original, result) {
+            eprintln!("{}", e);
+        }
+    }
+    if settings.keep_reviews {
+        if let Err(e) = append_review(settings, action, original, result) {
+            eprintln!("{}", e);
+        }
+    }
+}
+
+// Pinned reviews, oldest first. Lines that can't be parsed are skipped.
+#[tauri::command]
+fn list_pinned_reviews() -> Result<Vec<PinnedReview>, String> {
+    let content = match fs::read_to_string(get_reviews_path()) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("Failed to read reviews: {}", e)),
+    };
+    Ok(content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str::<PinnedReview>(line) {
+            Ok(review) => Some(review),
+            Err(e) => {
+                eprintln!("Skipping unreadable review: {}", e);
+                None
+            }
+        })
+        .collect())
+}
+
+// Plays `sound` (a file path) if given, otherwise the platform's completion sound
+fn play_completion_sound(sound: Option<&str>) {
+    #[cfg(target_os = "macos")]
+    {
+        use std::process::Command;
+        let _ = Command::new("afplay")
+            .arg(sound.unwrap_or("/System/Library/Sounds/Glass.aiff"))
+            .spawn();
+    }
+
+    // WAV files only. Without one, or if it can't be played, the sound
+    // assigned to "Default Beep" in the Windows sound scheme.
+    #[cfg(target_os = "windows")]
+    {
+        use windows_sys::Win32::Media::Audio::{
+            PlaySoundW, SND_ASYNC, SND_FILENAME, SND_NODEFAULT,
+        };
+        let played = sound.is_some_and(|path| {
+            let path: Vec<u16> = path.encode_utf16().chain(std::iter::once(0)).collect();
+            unsafe { PlaySoundW(path.as_ptr(), 0, SND_FILENAME | SND_ASYNC | SND_NODEFAULT) != 0 }
+        });
+        if !played {
+            unsafe {
+                windows_sys::Win32::System::Diagnostics::Debug::MessageBeep(
+                    windows_sys::Win32::UI::WindowsAndMessaging::MB_OK,
+                );
+            }
+        }
+    }
+
+    // PulseAudio/PipeWire with the freedesktop sound theme, else libcanberra
+    #[cfg(target_os = "linux")]
+    {
+        use std::process::Command;
+        let played = Command::new("paplay")
+            .arg(sound.unwrap_or("/usr/share/sounds/freedesktop/stereo/complete.oga"))
+            .spawn();
+        if played.is_err() {
+            let _ = match sound {
+                Some(path) => Command::new("canberra-gtk-play").args(["-f", path]).spawn(),
+                None => Command::new("canberra-gtk-play")
+                    .args(["--id", "complete"])
+                    .spawn(),
+            };
+        }
+    }
+}
+
+// The platform's error sound, for failures the user would otherwise miss
+fn play_error_sound() {
+    #[cfg(target_os = "macos")]
+    {
+        let _ = std::process::Command::new("afplay")
+            .arg("/System/Library/Sounds/Basso.aiff")
+            .spawn();
+    }
+
+    #[cfg(target_os = "windows")]
+    unsafe {
+        windows_sys::Win32::System::Diagnostics::Debug::MessageBeep(
+            windows_sys::Win32::UI::WindowsAndMessaging::MB_ICONHAND,
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        use std::process::Command;
+        let played = Command::new("paplay")
+            .arg("/usr/share/sounds/freedesktop/stereo/dialog-error.oga")
+            .spawn();
+        if played.is_err() {
+            let _ = Command::new("canberra-gtk-play")
+                .args(["--id", "dialog-error"])
+                .spawn();
+        }
+    }
+}
+
+// Titles of notifications that aren't about one action, keyed as in
+// `notification_strings`
+const NOTIFICATION_TITLES: [(&str, &str); 10] = [
+    ("truncated", "Output May Be Truncated"),
+    ("low_confidence", "Low-Confidence Translation"),
+    ("language_changed", "Language Changed"),
+    ("creative_retry_failed", "Creative Retry Failed"),
+    ("last_result_copied", "Last Result Copied"),
+    ("daily_limit", "Daily Limit Reached"),
+    ("replace_failed", "Replace Failed"),
+    ("copy_failed", "Failed to Copy Result"),
+    ("open_config_dir_failed", "Open Config Folder Failed"),
+    ("needs_attention", "Polish Language Needs Attention"),
+];
+
+// For results; failures go through `notify_error`, which has its own switch
+fn show_notification(
+    app_handle: &tauri::AppHandle,
+    title: &str,
+    body: &str,
+    urgency: NotificationUrgency,
+    settings: &Settings,
+) {
+    if settings.notify_on_success {
+        post_notification(app_handle, title, body, urgency, settings);
+    }
+}
+
+fn open_settings_window(app_handle: &tauri::AppHandle) {
+    if let Some(window) = app_handle.get_window("settings") {
+        window.show().unwrap();
+        window.set_focus().unwrap();
+    } else {
+        tauri::WindowBuilder::new(
+            app_handle,
+            "settings",
+            tauri::WindowUrl::App("index.html".into()),
+        )
+        .title("Polish Language - Settings")
+        .inner_size(500.0, 600.0)
+        .resizable(false)
+        .build()
+        .unwrap();
+    }
+}
+
+// Missing, invalid or unauthorized API keys can only be fixed in the settings
+fn is_auth_error(error: &str) -> bool {
+    error.contains("API key not configured")
+        || error.contains("status: 401")
+        || error.contains("status: 403")
+}
+
+// Reports a failed action. Notifications can't carry actions, so for auth
+// errors the settings window is opened instead.
+fn notify_error(
+    app_handle: &tauri::AppHandle,
+    action: Option<TextAction>,
+    title: &str,
+    error: &str,
+    settings: &Settings,
+) {
+    // Request errors can include the URL, which holds the key for Gemini
+    let error = &redact_error(error, settings);
+    eprintln!("{}", error);
+    record_error(app_handle, action, title, error);
+    if is_auth_error(error) && settings.open_settings_on_auth_error {
+        send_notification(
+            app_handle,
+            title,
+            &format!("{}\nCheck your API key in the settings.", error),
+            settings,
+        );
+        open_settings_window(app_handle);
+    } else if settings.notify_on_error {
+        post_notification(
+            app_handle,
+            title,
+            error,
+            settings.error_notification_urgency,
+            settings,
+        );
+    }
+}
+
+#[derive(Serialize, Clone)]
+struct ErrorInfo {
+    timestamp: String,
+    action: Option<TextAction>,
+    title: String,
+    message: String,
+}
+
+// The most recent failure, so the settings window can show it after the
+// notification is gone
+#[derive(Default)]
+struct LastError(Mutex<Option<ErrorInfo>>);
+
+fn record_error(
+    app_handle: &tauri::AppHandle,
+    action: Option<TextAction>,
+    title: &str,
+    message: &str,
+) {
+    let info = ErrorInfo {
+        timestamp: chrono::Local::now().to_rfc3339(),
+        action,
+        title: title.to_string(),
+        message: message.to_string(),
+    };
+    *app_handle.state::<LastError>().0.lock().unwrap() = Some(info.clone());
+    if let Err(e) = app_handle.emit_all("error:new", &info) {
+        eprintln!("Failed to emit error:new: {}", e);
+    }
+}
+
+#[tauri::command]
+fn get_last_error(last_error: tauri::State<LastError>) -> Option<ErrorInfo> {
+    last_error.0.lock().unwrap().clone()
+}
+
+#[tauri::command]
+fn clear_last_error(last_error: tauri::State<LastError>) {
+    *last_error.0.lock().unwrap() = None;
+}
+
+// Bypasses `notify_on_success` and `notify_on_error`, for warnings the user must not miss
+fn send_notification(app_handle: &tauri::AppHandle, title: &str, body: &str, settings: &Settings) {
+    post_notification(
+        app_handle,
+        title,
+        body,
+        NotificationUrgency::Normal,
+        settings,
+    );
+}
+
+// Urgency and timeout are only honored by Linux notification servers; Tauri's
+// notification API has neither, so elsewhere they are ignored
+fn post_notification(
+    app_handle: &tauri::AppHandle,
+    title: &str,
+    body: &str,
+    urgency: NotificationUrgency,
+    settings: &Settings,
+) {
+    #[cfg(target_os = "linux")]
+    {
+        let mut notification = notify_rust::Notification::new();
+        notification
+            .appname(&app_handle.package_info().name)
+            .summary(title)
+            .body(body)
+            .auto_icon()
+            .urgency(match urgency {
+                NotificationUrgency::Low => notify_rust::Urgency::Low,
+                NotificationUrgency::Normal => notify_rust::Urgency::Normal,
+                NotificationUrgency::Critical => notify_rust::Urgency::Critical,
+            });
+        if let Some(timeout_ms) = settings.notification_timeout_ms {
+            notification.timeout(Duration::from_millis(timeout_ms));
+        }
+        if let Err(e) = notification.show() {
+            eprintln!("Failed to show notification: {}", e);
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (urgency, settings);
+        let _ = tauri::api::notification::Notification::new(
+            &app_handle.config().tauri.bundle.identifier,
+        )
+        .title(title)
+        .body(body)
+        .show();
+    }
+}
+
+// Cleans up pasted-in oddities: non-breaking spaces, runs of whitespace and
+// trailing spaces, and smart quotes with `straighten_quotes`. Leading
+// indentation is kept.
+fn normalize_text(text: &str, settings: &Settings) -> String {
+    text.lines()
+        .map(|line| {
+            let line: String = line
+                .chars()
+                .map(|c| match c {
+                    '\u{00A0}' | '\u{2007}' | '\u{202F}' => ' ',
+                    '\u{2018}' | '\u{2019}' if settings.straighten_quotes => '\'',
+                    '\u{201C}' | '\u{201D}' if settings.straighten_quotes => '"',
+                    c => c,
+                })
+                .collect();
+            let rest = line.trim_start();
+            if rest.is_empty() {
+                return String::new();
+            }
+            let indent = &line[..line.len() - rest.len()];
+            format!(
+                "{}{}",
+                indent,
+                rest.split_whitespace().collect::<Vec<_>>().join(" ")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// Lead-ins models add despite being told to return only the text. Matched
+// case-insensitively against a short first line ending in a colon.
+const PREAMBLE_PREFIXES: &[&str] = &[
+    "here is",
+    "here's",
+    "sure",
+    "certainly",
+    "below is",
+    "polished text",
+    "improved text",
+    "revised text",
+    "corrected text",
+    "translated text",
+    "translation",
+];
+
+// Closing remarks that only ever appear as chatter after the answer
+const EPILOGUE_PREFIXES: &[&str] = &[
+    "let me know if",
+    "i hope this helps",
+    "hope this helps",
+    "feel free to",
+];
+
+// Strips preambles, closing chatter, code fences and wrapping quotes that the
+// model put around its answer.
+fn clean_response(text: &str) -> String {
+    let mut text = text.trim();
+
+    // "Here is the polished text:" on its own line
+    if let Some((first_line, rest)) = text.split_once('\n') {
+        let lower = first_line.trim().to_lowercase();
+        if lower.len() <= 80
+            && lower.ends_with(':')
+            && PREAMBLE_PREFIXES.iter().any(|p| lower.starts_with(p))
+        {
+            text = rest.trim();
+        }
+    }
+
+    // "...\n\nLet me know if you need anything else!"
+    if let Some((body, last_paragraph)) = text.rsplit_once("\n\n") {
+        let lower = last_paragraph.trim().to_lowercase();
+        if EPILOGUE_PREFIXES.iter().any(|p| lower.starts_with(p)) {
+            text = body.trim();
+        }
+    }
+
+    // ```lang\n...\n```
+    if text.starts_with("```") && text.ends_with("```") && text.len() > 6 {
+        if let Some((_, inner)) = text[..text.len() - 3].split_once('\n') {
+            text = inner.trim();
+        }
+    }
+
+    // Quotes around the whole answer, but only if they don't appear inside it
+    for (open, close) in [('"', '"'), ('\u{201C}', '\u{201D}'), ('\'', '\'')] {
+        if let Some(inner) = text.strip_prefix(open).and_then(|t| t.strip_suffix(close)) {
+            if !inner.contains(open) && !inner.contains(close) {
+                text = inner.trim();
+                break;
+            }
+        }
+    }
+
+    text.to_string()
+}
+
+// Where in the pipeline the result is. The template splits it: steps listed
+// before it run on the model's answer, before it is used or recorded, and the
+// rest on the text about to be copied, typed or previewed.
+enum PostStage<'a> {
+    Answer {
+        redactions: &'a [String],
+        code: bool, // Code keeps its formatting, apart from unmasking
+    },
+    Result(TextAction),
+}
+
+impl PostStage<'_> {
+    // Masked values are only kept for the request, so unmasking runs on the
+    // answer wherever it is listed
+    fn includes(&self, steps: &[PostProcessor], index: usize) -> bool {
+        let template = steps
+            .iter()
+            .position(|step| *step == PostProcessor::Template)
+            .unwrap_or(steps.len());
+        let on_answer = index < template || steps[index] == PostProcessor::UnmaskRedactions;
+        matches!(self, PostStage::Answer { .. }) == on_answer
+    }
+}
+
+// Runs the configured post-processors that belong to `stage`, in order
+fn apply_post_processing(result: String, settings: &Settings, stage: &PostStage) -> String {
+    let code = match stage {
+        PostStage::Answer { code, .. } => *code,
+        PostStage::Result(action) => *action == TextAction::CodePolish,
+    };
+    let steps = &settings.post_processors;
+    let mut text = result;
+    for (index, step) in steps.iter().enumerate() {
+        if !stage.includes(steps, index) {
+            continue;
+        }
+        text = match step {
+            PostProcessor::Trim if !code => text.trim().to_string(),
+            PostProcessor::StripPreamble if !code && settings.strip_preamble => {
+                clean_response(&text)
+            }
+            PostProcessor::Wrap if !code => match settings.wrap_width {
+                Some(width) if width > 0 => wrap_text(&text, width),
+                _ => text,
+            },
+            PostProcessor::Template => match stage {
+                PostStage::Result(action) => settings.apply_result_template(*action, text),
+                PostStage::Answer { .. } => text,
+            },
+            PostProcessor::UnmaskRedactions => match stage {
+                PostStage::Answer { redactions, .. } if !redactions.is_empty() => {
+                    let (restored, missing) = unredact(&text, redactions);
+                    if missing > 0 {
+                        eprintln!(
+                            "{} of {} redacted values were dropped by the model",
+                            missing,
+                            redactions.len()
+                        );
+                    }
+                    restored
+                }
+                _ => text,
+            },
+            _ => text,
+        };
+    }
+    text
+}
+
+// Hard-wraps each paragraph at `width` columns. Blank lines, list items and
+// fenced code blocks keep their line breaks.
+fn wrap_text(text: &str, width: usize) -> String {
+    let mut lines = Vec::new();
+    let mut paragraph: Vec<&str> = Vec::new();
+    let mut in_code_block = false;
+
+    let flush = |paragraph: &mut Vec<&str>, lines: &mut Vec<String>| {
+        if !paragraph.is_empty() {
+            lines.push(textwrap::fill(&paragraph.join(" "), width));
+            paragraph.clear();
+        }
+    };
+
+    for line in text.lines() {
+        let trimmed = line.trim();
+        let is_fence = trimmed.starts_with("```");
+        if in_code_block || is_fence || trimmed.is_empty() {
+            flush(&mut paragraph, &mut lines);
+            lines.push(line.to_string());
+            if is_fence {
+                in_code_block = !in_code_block;
+            }
+            continue;
+        }
+
+        let is_list_item = trimmed.starts_with("- ")
+            || trimmed.starts_with("* ")
+            || trimmed
+                .split_once(". ")
+                .is_some_and(|(number, _)| number.chars().all(|c| c.is_ascii_digit()));
+        if is_list_item {
+            flush(&mut paragraph, &mut lines);
+        }
+        paragraph.push(trimmed);
+    }
+    flush(&mut paragraph, &mut lines);
+
+    lines.join("\n")
+}
+
+fn format_clipboard(original: &str, result: &str, format: ClipboardFormat) -> String {
+    match format {
+        ClipboardFormat::ResultOnly => result.to_string(),
+        ClipboardFormat::OriginalAndResult => {
+            format!("Original:\n{}\n\nPolished:\n{}", original.trim(), result)
+        }
+        ClipboardFormat::UnifiedDiff => {
+            // Single paragraphs read better as a word diff than as one replaced line
+            if !original.trim().contains('\n') && !result.contains('\n') {
+                let diff = TextDiff::from_words(original.trim(), result);
+                diff.iter_all_changes()
+                    .map(|change| match change.tag() {
+                        ChangeTag::Equal => change.value().to_string(),
+                        ChangeTag::Delete => format!("[-{}-]", change.value()),
+                        ChangeTag::Insert => format!("{{+{}+}}", change.value()),
+                    })
+                    .collect()
+            } else {
+                TextDiff::from_lines(original, result)
+                    .unified_diff()
+                    .header("original", "polished")
+                    .to_string()
+            }
+        }
+    }
+}
+
+// Notifications only have room for the start of the text
+fn notification_preview(text: &str) -> String {
+    if text.chars().count() > 100 {
+        format!("{}...", text.chars().take(97).collect::<String>())
+    } else {
+        text.to_string()
+    }
+}
+
+fn format_text_stats(label: &str, original: &str, result: &str) -> String {
+    format!(
+        "{}: {} → {} chars, {} → {} words",
+        label,
+        original.trim().chars().count(),
+        result.chars().count(),
+        original.split_whitespace().count(),
+        result.split_whitespace().count()
+    )
+}
+
+fn warn_if_truncated(app_handle: &tauri::AppHandle, output: &LlmOutput, settings: &Settings) {
+    if output.truncated {
+        eprintln!("Model output hit the max_tokens limit and may be truncated");
+        send_notification(
+            app_handle,
+            settings.title("truncated"),
+            "The model stopped at its output token limit, so the result may be cut off. Try a shorter selection or a higher max_tokens.",
+            settings,
+        );
+    }
+}
+
+// Types or pastes `text` over the current selection in the frontmost app. Requires
+// the Accessibility permission on macOS, otherwise the key events are silently dropped.
+async fn replace_selection(
+    app_handle: &tauri::AppHandle,
+    text: String,
+    settings: &Settings,
+) -> Result<(), String> {
+    #[cfg(target_os = "macos")]
+    {
+        if !unsafe { accessibility_sys_ng::AXIsProcessTrusted() } {
+            return Err("Replace mode needs the Accessibility permission. Enable Polish Language in System Settings > Privacy & Security > Accessibility.".to_string());
+        }
+        if settings.ax_direct_write {
+            match ax_write_selection(&text) {
+                Ok(()) => return Ok(()),
+                Err(e) => eprintln!("{}, falling back to simulated input", e),
+            }
+        }
+    }
+
+    let method = settings.replace_method;
+    if method == ReplaceMethod::Paste {
+        write_clipboard(app_handle, &text, settings).await?;
+    }
+    let typing_delay = Duration::from_millis(settings.typing_delay_ms);
+
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut enigo = Enigo::new(&enigo::Settings::default())
+            .map_err(|e| format!("Failed to start input simulation: {}", e))?;
+        // Let the shortcut's modifier keys come up so they don't combine with the typed text
+        std::thread::sleep(Duration::from_millis(150));
+        match method {
+            ReplaceMethod::Paste => paste_keystroke(&mut enigo),
+            ReplaceMethod::Type if typing_delay.is_zero() => enigo
+                .text(&text)
+                .map_err(|e| format!("Failed to type text: {}", e)),
+            ReplaceMethod::Type => {
+                let mut buf = [0; 4];
+                for c in text.chars() {
+                    enigo
+                        .text(c.encode_utf8(&mut buf))
+                        .map_err(|e| format!("Failed to type text: {}", e))?;
+                    std::thread::sleep(typing_delay);
+                }
+                Ok(())
+            }
+        }
+    })
+    .await
+    .map_err(|e| format!("Input simulation task failed: {}", e))?
+}
+
+// Replaces the selection in the focused text field or text area by setting its
+// AXSelectedText, which avoids input simulation and the clipboard entirely
+#[cfg(target_os = "macos")]
+fn ax_write_selection(text: &str) -> Result<(), String> {
+    use accessibility_sys_ng::{
+        kAXErrorSuccess, kAXFocusedUIElementAttribute, kAXRoleAttribute, kAXSelectedTextAttribute,
+        kAXTextAreaRole, kAXTextFieldRole, AXUIElementCopyAttributeValue,
+        AXUIElementCreateSystemWide, AXUIElementIsAttributeSettable, AXUIElementRef,
+        AXUIElementSetAttributeValue,
+    };
+    use core_foundation::base::{CFType, CFTypeRef, TCFType};
+    use core_foundation::string::CFString;
+
+    // The returned value is owned, so wrapping it releases it when dropped
+    let copy_attribute = |element: AXUIElementRef, attribute: &str| {
+        let mut value: CFTypeRef = std::ptr::null();
+        let attribute = CFString::new(attribute);
+        let error = unsafe {
+            AXUIElementCopyAttributeValue(element, attribute.as_concrete_TypeRef(), &mut value)
+        };
+        (error == kAXErrorSuccess && !value.is_null())
+            .then(|| unsafe { CFType::wrap_under_create_rule(value) })
+    };
+
+    let system_wide =
+        unsafe { CFType::wrap_under_create_rule(AXUIElementCreateSystemWide() as CFTypeRef) };
+    let focused = copy_attribute(
+        system_wide.as_CFTypeRef() as AXUIElementRef,
+        kAXFocusedUIElementAttribute,
+    )
+    .ok_or("No focused element to write to")?;
+    let element = focused.as_CFTypeRef() as AXUIElementRef;
+    let role = copy_attribute(element, kAXRoleAttribute)
+        .and_then(|role| role.downcast::<CFString>())
+        .map(|role| role.to_string())
+        .unwrap_or_default();
+    if role != kAXTextFieldRole && role != kAXTextAreaRole {
+        return Err(format!(
+            "The focused element is not a text field ({})",
+            role
+        ));
+    }
+
+    let attribute = CFString::new(kAXSelectedTextAttribute);
+    let mut settable = 0;
+    let error = unsafe {
+        AXUIElementIsAttributeSettable(element, attribute.as_concrete_TypeRef(), &mut settable)
+    };
+    if error != kAXErrorSuccess || settable == 0 {
+        return Err("The text field doesn't allow replacing its selection".to_string());
+    }
+    let value = CFString::new(text);
+    let error = unsafe {
+        AXUIElementSetAttributeValue(
+            element,
+            attribute.as_concrete_TypeRef(),
+            value.as_CFTypeRef(),
+        )
+    };
+    if error != kAXErrorSuccess {
+        return Err(format!(
+            "Failed to write to the text field (AXError {})",
+            error
+        ));
+    }
+    Ok(())
+}
+
+fn paste_keystroke(enigo: &mut Enigo) -> Result<(), String> {
+    #[cfg(target_os = "macos")]
+    let modifier = Key::Meta;
+    #[cfg(not(target_os = "macos"))]
+    let modifier = Key::Control;
+
+    let to_error = |e: enigo::InputError| format!("Failed to paste text: {}", e);
+    enigo.key(modifier, Direction::Press).map_err(to_error)?;
+    let pasted = enigo
+        .key(Key::Unicode('v'), Direction::Click)
+        .map_err(to_error);
+    enigo.key(modifier, Direction::Release).map_err(to_error)?;
+    pasted
+}
+
+// Announces that a request is running once `start_notification_delay_ms` has
+// passed. Aborting the task when the result arrives keeps fast requests quiet.
+fn spawn_start_notification(
+    app_handle: &tauri::AppHandle,
+    action: TextAction,
+    settings: &Settings,
+) -> tauri::async_runtime::JoinHandle<()> {
+    let app_handle = app_handle.clone();
+    let settings = settings.clone();
+    let delay = Duration::from_millis(settings.start_notification_delay_ms);
+    let title = settings
+        .action_title(action, "started", action.label())
+        .to_string();
+    let body = format!(
+        "Waiting for {} to {} the text...",
+        settings.provider,
+        action.verb()
+    );
+    tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(delay).await;
+        send_notification(&app_handle, &title, &body, &settings);
+    })
+}
+
+const CODE_POLISH_PROMPT: &str = "Improve the readability of the following code without changing its behavior: clearer names, simpler structure and consistent formatting. Keep the language, indentation style and comments. Only return the code, without explanations or code fences:";
+
+// Keywords that start lines of code in common languages
+const CODE_KEYWORDS: &[&str] = &[
+    "fn",
+    "pub",
+    "let",
+    "const",
+    "var",
+    "function",
+    "def",
+    "class",
+    "struct",
+    "enum",
+    "impl",
+    "import",
+    "from",
+    "use",
+    "return",
+    "if",
+    "else",
+    "elif",
+    "for",
+    "while",
+    "switch",
+    "case",
+    "try",
+    "catch",
+    "except",
+    "public",
+    "private",
+    "static",
+    "async",
+    "await",
+    "#include",
+    "package",
+    "func",
+    "type",
+    "interface",
+    "SELECT",
+    "UPDATE",
+    "INSERT",
+];
+
+// A rough guess at whether a selection is source code rather than prose. Lines
+// count as code when they end in a brace or semicolon, start with a keyword, or
+// are indented under a line that opens a block. Needs at least two lines and
+// half of them to look like code, so wrapped prose rarely qualifies.
+fn looks_like_code(text: &str) -> bool {
+    if split_code_fence(text).is_some() {
+        return true;
+    }
+    let lines: Vec<&str> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .collect();
+    if lines.len() < 2 {
+        return false;
+    }
+    let mut code_lines = 0;
+    let mut opens_block = false;
+    for line in &lines {
+        let trimmed = line.trim();
+        let indented = line.starts_with([' ', '\t']);
+        let first_word = trimmed
+            .split(|c: char| c.is_whitespace() || c == '(' || c == ':')
+            .next()
+            .unwrap_or_default();
+        if trimmed.ends_with(['{', '}', ';', '[', ']', '('])
+            || trimmed.ends_with("=>")
+            || CODE_KEYWORDS.contains(&first_word)
+            || (indented && opens_block && !trimmed.starts_with(['-', '*']))
+        {
+            code_lines += 1;
+        }
+        opens_block = trimmed.ends_with(['{', ':', '(', '[']);
+    }
+    code_lines * 2 >= lines.len()
+}
+
+// Splits "```lang\n...\n```" into the fence's info string and the code inside,
+// keeping the code's indentation
+fn split_code_fence(text: &str) -> Option<(&str, &str)> {
+    let inner = text.trim().strip_prefix("```")?.strip_suffix("```")?;
+    let (info, code) = inner.split_once('\n')?;
+    if info.contains('`') {
+        return None;
+    }
+    let code = code.strip_suffix('\n').unwrap_or(code);
+    Some((info.trim(), code.strip_suffix('\r').unwrap_or(code)))
+}
+
+// Splits an improve_and_explain answer into the text and the list of changes.
+// Models sometimes drop the delimiter, in which case it is all treated as text.
+fn split_explanation(answer: &str) -> (String, Option<String>) {
+    match answer.split_once(EXPLANATION_DELIMITER) {
+        Some((text, explanation)) => {
+            let explanation = explanation.trim();
+            (
+                text.trim().to_string(),
+                (!explanation.is_empty()).then(|| explanation.to_string()),
+            )
+        }
+        None => (answer.to_string(), None),
+    }
+}
+
+fn warn_if_low_confidence(app_handle: &tauri::AppHandle, output: &LlmOutput, settings: &Settings) {
+    let confidence = output
+        .translation_details
+        .as_ref()
+        .and_then(|details| details.confidence);
+    if let Some(confidence) = confidence.filter(|c| *c < LOW_CONFIDENCE_THRESHOLD) {
+        send_notification(
+            app_handle,
+            settings.title("low_confidence"),
+            &format!(
+                "The model rated its translation {:.0}% confident. Please review it before use.",
+                confidence * 100.0
+            ),
+            settings,
+        );
+    }
+}
+
+// The input's and the output's language, when both are detected reliably and differ
+fn language_change(input: &str, output: &str) -> Option<(&'static str, &'static str)> {
+    let long_enough = |text: &str| text.trim().chars().count() >= MIN_LANGUAGE_CHECK_CHARS;
+    if !long_enough(input) || !long_enough(output) {
+        return None;
+    }
+    let expected = detect_language(input)?;
+    let actual = detect_language(output)?;
+    (expected != actual).then_some((expected, actual))
+}
+
+// Catches edits that came back translated, retrying once with a firmer prompt
+// when `retry_on_language_change` is set and otherwise warning about it
+async fn verify_language(
+    app_handle: &tauri::AppHandle,
+    input: &str,
+    output: LlmOutput,
+    spec: &PromptSpec<'_>,
+    settings: &Settings,
+) -> LlmOutput {
+    let Some((expected, actual)) = language_change(input, &output.text) else {
+        return output;
+    };
+    eprintln!("Input is {} but the result is {}", expected, actual);
+
+    if settings.retry_on_language_change {
+        let firmer = PromptSpec {
+            prompt: format!(
+                "The text is in {0}. Your answer must also be in {0}; do not translate it.\n\n{1}",
+                expected, spec.prompt
+            ),
+            ..spec.clone()
+        };
+        match process_text_with_llm(app_handle, input, &firmer, settings, None).await {
+            Ok(retried) if language_change(input, &retried.text).is_none() => return retried,
+            Ok(_) => eprintln!("Retry still changed the language"),
+            Err(e) => eprintln!("Language retry failed: {}", e),
+        }
+    }
+
+    send_notification(
+        app_handle,
+        settings.title("language_changed"),
+        &format!(
+            "The selection looks like {} but the result looks like {}. The model may have translated it, so please review the result.",
+            expected, actual
+        ),
+        settings,
+    );
+    output
+}
+
+fn format_translation_details(details: &TranslationDetails) -> String {
+    let language = details.detected_language.as_deref().unwrap_or("unknown");
+    match details.confidence {
+        Some(confidence) => format!(
+            "Detected language: {}, confidence {:.0}%",
+            language,
+            confidence * 100.0
+        ),
+        None => format!("Detected language: {}", language),
+    }
+}
+
+// Tray item ids for one-off translations are this prefix plus the language
+const TRANSLATE_MENU_PREFIX: &str = "translate:";
+
+// Translates the selection into `language` instead of English
+fn translate_to_binding(language: &str) -> ShortcutBinding {
+    ShortcutBinding {
+        shortcut: String::new(),
+        action: TextAction::Translate,
+        prompt: Some(format!(
+            "Translate the following text to {}. If the text is already in {}, keep it as is. Only return the translated text without any additional explanation:",
+            language, language
+        )),
+        model: None,
+        from_clipboard: false,
+        case: None,
+    }
+}
+
+// Tray item ids for case conversions are this prefix plus CaseMode::id
+const CASE_MENU_PREFIX: &str = "case:";
+
+fn convert_case_binding(case: CaseMode) -> ShortcutBinding {
+    ShortcutBinding {
+        shortcut: String::new(),
+        action: TextAction::ConvertCase,
+        prompt: None,
+        model: None,
+        from_clipboard: false,
+        case: Some(case),
+    }
+}
+
+// Informational items show the configured shortcuts and whether they're
+// actually registered. The menu is rebuilt by refresh_tray_menu whenever the
+// settings or shortcut registrations change.
+fn build_tray_menu(settings: &Settings, is_registered: &dyn Fn(&str) -> bool) -> SystemTrayMenu {
+    let shortcut_item = |id: &str, label: &str, shortcut: &str| {
+        let state = if is_registered(shortcut) {
+            ""
+        } else {
+            " (inactive)"
+        };
+        tauri::CustomMenuItem::new(id.to_string(), format!("{}: {}{}", label, shortcut, state))
+            .disabled()
+    };
+
+    let mut menu = SystemTrayMenu::new();
+    for (index, binding) in settings.all_shortcut_bindings().iter().enumerate() {
+        menu = menu.add_item(shortcut_item(
+            &format!("shortcut_{}", index),
+            binding.label(),
+            &binding.shortcut,
+        ));
+    }
+
+    if !settings.recall_shortcut.is_empty() {
+        menu = menu.add_item(shortcut_item(
+            "shortcut_recall",
+            "Recall",
+            &settings.recall_shortcut,
+        ));
+    }
+
+    if !settings.regenerate_shortcut.is_empty() {
+        menu = menu.add_item(shortcut_item(
+            "shortcut_regenerate",
+            "Creative Retry",
+            &settings.regenerate_shortcut,
+        ));
+    }
+
+    if !settings.settings_shortcut.is_empty() {
+        menu = menu.add_item(shortcut_item(
+            "shortcut_settings",
+            "Open Settings",
+            &settings.settings_shortcut,
+        ));
+    }
+
+    // Only registered while a retry is offered, so it's never marked inactive
+    if !settings.retry_shortcut.is_empty() {
+        menu = menu.add_item(
+            tauri::CustomMenuItem::new(
+                "shortcut_retry".to_string(),
+                format!("Retry After a Failure: {}", settings.retry_shortcut),
+            )
+            .disabled(),
+        );
+    }
+
+    let languages: Vec<&str> = settings
+        .target_languages
+        .iter()
+        .map(|language| language.trim())
+        .filter(|language| !language.is_empty())
+        .collect();
+    if !languages.is_empty() {
+        let submenu = languages
+            .into_iter()
+            .fold(SystemTrayMenu::new(), |submenu, language| {
+                submenu.add_item(tauri::CustomMenuItem::new(
+                    format!("{}{}", TRANSLATE_MENU_PREFIX, language),
+                    language,
+                ))
+            });
+        menu = menu
+            .add_native_item(tauri::SystemTrayMenuItem::Separator)
+            .add_submenu(tauri::SystemTraySubmenu::new("Translate To", submenu));
+    }
+
+    let cases = CaseMode::ALL
+        .into_iter()
+        .fold(SystemTrayMenu::new(), |submenu, case| {
+            submenu.add_item(tauri::CustomMenuItem::new(
+                format!("{}{}", CASE_MENU_PREFIX, case.id()),
+                case.label(),
+            ))
+        });
+    menu = menu
+        .add_native_item(tauri::SystemTrayMenuItem::Separator)
+        .add_submenu(tauri::SystemTraySubmenu::new("Convert Case", cases));
+
+    menu.add_native_item(tauri::SystemTrayMenuItem::Separator)
+        .add_item(tauri::CustomMenuItem::new(
+            "settings".to_string(),
+            "Settings",
+        ))
+        .add_item(tauri::CustomMenuItem::new(
+            "open_config_dir".to_string(),
+            "Open Config Folder",
+        ))
+        .add_native_item(tauri::SystemTrayMenuItem::Separator)
+        .add_item(tauri::CustomMenuItem::new("quit".to_string(), "Quit"))
+}
+
+// The input, result and case of the last cycling conversion
+#[derive(Default)]
+struct CaseCycle(Mutex<Option<(String, String, CaseMode)>>);
+
+// The cycling case shortcut starts with the first case and moves on to the
+// next one when pressed again on the same text, or on the previous result
+// after it replaced the selection
+fn next_case(app_handle: &tauri::AppHandle, text: &str) -> CaseMode {
+    let state = app_handle.state::<CaseCycle>();
+    let mut last = state.0.lock().unwrap();
+    let case = match &*last {
+        Some((input, result, case)) if input == text || result == text => case.next(),
+        _ => CaseMode::ALL[0],
+    };
+    *last = Some((text.to_string(), transforms::convert_case(text, case), case));
+    case
+}
+
+// When each shortcut last fired, to drop the repeats some keyboards send
+#[derive(Default)]
+struct ShortcutDebounce(Mutex<HashMap<String, Instant>>);
+
+// Whether `shortcut` already fired within the last `debounce_ms`. Ignored
+// triggers don't count, so holding a shortcut down doesn't lock it out.
+fn is_double_fire(app_handle: &tauri::AppHandle, shortcut: &str, debounce_ms: u64) -> bool {
+    if debounce_ms == 0 {
+        return false;
+    }
+    let state = app_handle.state::<ShortcutDebounce>();
+    let mut last_fired = state.0.lock().unwrap();
+    let now = Instant::now();
+    let key = normalize_accelerator(shortcut);
+    if let Some(last) = last_fired.get(&key) {
+        if now.duration_since(*last) < Duration::from_millis(debounce_ms) {
+            eprintln!("Ignoring repeated {} shortcut", shortcut);
+            return true;
+        }
+    }
+    last_fired.insert(key, now);
+    false
+}
+
+// Replaces all global shortcuts with the ones in `settings`
+fn register_shortcuts(app_handle: &tauri::AppHandle, settings: &Settings) {
+    let mut shortcut_manager = app_handle.global_shortcut_manager();
+    if let Err(e) = shortcut_manager.unregister_all() {
+        eprintln!("Failed to unregister shortcuts: {}", e);
+    }
+    // That included any pending retry offer
+    app_handle.state::<RetryOffer>().0.lock().unwrap().shortcut = None;
+
+    // settings.json may have been edited by hand, so skip duplicates rather
+    // than failing
+    let mut registered = HashSet::new();
+    let debounce_ms = settings.debounce_ms;
+    for binding in settings.all_shortcut_bindings() {
+        if !registered.insert(normalize_accelerator(&binding.shortcut)) {
+            eprintln!("Skipping duplicate shortcut {}", binding.shortcut);
+            continue;
+        }
+        if binding.action == TextAction::Custom && binding.prompt.is_none() {
+            eprintln!(
+                "Skipping custom shortcut {} without a prompt",
+                binding.shortcut
+            );
+            continue;
+        }
+
+        let shortcut = binding.shortcut.clone();
+        let verb = binding.action.verb();
+        let app_handle_action = app_handle.clone();
+        shortcut_manager
+            .register(&shortcut, move || {
+                if is_double_fire(&app_handle_action, &binding.shortcut, debounce_ms) {
+                    return;
+                }
+                tauri::async_runtime::spawn(handle_text_action(
+                    app_handle_action.clone(),
+                    binding.clone(),
+                ));
+            })
+            .unwrap_or_else(|e| eprintln!("Failed to register {} shortcut: {}", verb, e));
+    }
+
+    if !settings.recall_shortcut.is_empty()
+        && registered.insert(normalize_accelerator(&settings.recall_shortcut))
+    {
+        let app_handle_recall = app_handle.clone();
+        let shortcut = settings.recall_shortcut.clone();
+        shortcut_manager
+            .register(&settings.recall_shortcut, move || {
+                if is_double_fire(&app_handle_recall, &shortcut, debounce_ms) {
+                    return;
+                }
+                let app_handle = app_handle_recall.clone();
+                tauri::async_runtime::spawn(async move {
+                    if let Err(e) = copy_last_result(app_handle).await {
+                        eprintln!("Failed to recall last result: {}", e);
+                    }
+                });
+            })
+            .unwrap_or_else(|e| eprintln!("Failed to register recall shortcut: {}", e));
+    }
+
+    if !settings.regenerate_shortcut.is_empty()
+        && registered.insert(normalize_accelerator(&settings.regenerate_shortcut))
+    {
+        let app_handle_regenerate = app_handle.clone();
+        let shortcut = settings.regenerate_shortcut.clone();
+        shortcut_manager
+            .register(&settings.regenerate_shortcut, move || {
+                if is_double_fire(&app_handle_regenerate, &shortcut, debounce_ms) {
+                    return;
+                }
+                let app_handle = app_handle_regenerate.clone();
+                tauri::async_runtime::spawn(async move {
+                    if let Err(e) = regenerate_creative(app_handle.clone()).await {
+                        let settings = load_settings();
+                        notify_error(
+                            &app_handle,
+                            None,
+                            settings.title("creative_retry_failed"),
+                            &e,
+                            &settings,
+                        );
+                    }
+                });
+            })
+            .unwrap_or_else(|e| eprintln!("Failed to register regenerate shortcut: {}", e));
+    }
+
+    if !settings.settings_shortcut.is_empty()
+        && registered.insert(normalize_accelerator(&settings.settings_shortcut))
+    {
+        let app_handle_settings = app_handle.clone();
+        shortcut_manager
+            .register(&settings.settings_shortcut, move || {
+                open_settings_window(&app_handle_settings)
+            })
+            .unwrap_or_else(|e| eprintln!("Failed to register settings shortcut: {}", e));
+    }
+
+    refresh_tray_menu(app_handle, settings);
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+enum HealthCategory {
+    ApiKey,
+    BaseUrl,
+    Shortcuts,
+    #[cfg_attr(not(target_os = "macos"), allow(dead_code))] // Only checked on macOS
+    Accessibility,
+}
+
+#[derive(Serialize)]
+struct HealthIssue {
+    category: HealthCategory,
+    message: String,
+}
+
+fn check_health(app_handle: &tauri::AppHandle, settings: &Settings) -> Vec<HealthIssue> {
+    let mut issues = Vec::new();
+    let mut issue = |category, message: String| issues.push(HealthIssue { category, message });
+
+    if settings.get_current_api_key().is_empty() {
+        issue(
+            HealthCategory::ApiKey,
+            format!("No API key configured for {}", settings.provider),
+        );
+    }
+
+    let base_url = settings.get_current_base_url();
+    let valid_url = reqwest::Url::parse(&base_url)
+        .is_ok_and(|url| matches!(url.scheme(), "http" | "https") && url.has_host());
+    if !valid_url {
+        issue(
+            HealthCategory::BaseUrl,
+            format!("Base URL is not a valid http(s) URL: {}", base_url),
+        );
+    }
+
+    if let Err(e) = settings.validate_shortcuts() {
+        issue(HealthCategory::Shortcuts, e);
+    }
+    // Registration fails for malformed accelerators and ones another app holds
+    let shortcut_manager = app_handle.global_shortcut_manager();
+    for binding in settings.all_shortcut_bindings() {
+        if !shortcut_manager
+            .is_registered(&binding.shortcut)
+            .unwrap_or(false)
+        {
+            issue(
+                HealthCategory::Shortcuts,
+                format!(
+                    "{} shortcut {} could not be registered",
+                    binding.label(),
+                    binding.shortcut
+                ),
+            );
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        if !unsafe { accessibility_sys_ng::AXIsProcessTrusted() } {
+            issue(
+                HealthCategory::Accessibility,
+                "Accessibility permission is missing, so selected text can't be read. Enable Polish Language in System Settings > Privacy & Security > Accessibility.".to_string(),
+            );
+        }
+    }
+
+    issues
+}
+
+#[tauri::command]
+fn get_health(app_handle: tauri::AppHandle) -> Vec<HealthIssue> {
+    check_health(&app_handle, &load_settings())
+}
+
+fn refresh_tray_menu(app_handle: &tauri::AppHandle, settings: &Settings) {
+    let shortcut_manager = app_handle.global_shortcut_manager();
+    let menu = build_tray_menu(settings, &|shortcut| {
+        shortcut_manager.is_registered(shortcut).unwrap_or(false)
+    });
+    if let Err(e) = app_handle.tray_handle().set_menu(menu) {
+        eprintln!("Failed to update tray menu: {}", e);
+    }
+    update_tray_counter(app_handle, settings);
+}
+
+// Shows today's request count next to the menu bar icon with `show_tray_counter`
+#[cfg(target_os = "macos")]
+fn update_tray_counter(app_handle: &tauri::AppHandle, settings: &Settings) {
+    let title = if settings.show_tray_counter {
+        load_usage().count.to_string()
+    } else {
+        String::new()
+    };
+    if let Err(e) = app_handle.tray_handle().set_title(&title) {
+        eprintln!("Warning: failed to update the tray title: {}", e);
+    }
+}
+
+// Tray titles are only supported in the macOS menu bar
+#[cfg(not(target_os = "macos"))]
+fn update_tray_counter(_app_handle: &tauri::AppHandle, _settings: &Settings) {}
+
+fn update_tray_icon_processing(app_handle: &tauri::AppHandle, processing: bool) {
+    let tray = app_handle.tray_handle();
+    // On macOS, we can change the tray icon to indicate processing
+    // For now, we'll just use the tooltip to show status
+    let tooltip = if processing {
+        "Polish Language - Processing..."
+    } else {
+        "Polish Language"
+    };
+    if let Err(e) = tray.set_tooltip(tooltip) {
+        eprintln!("Warning: failed to update the tray tooltip: {}", e);
+    }
+    // An action just finished, so the count may have gone up
+    if !processing {
+        update_tray_counter(app_handle, &load_settings());
+    }
+}
+
+// Compiled in, so the tray still gets an icon if the configured one is missing
+fn fallback_tray_icon() -> tauri::Icon {
+    tauri::Icon::Raw(include_bytes!("../icons/32x32.png").to_vec())
+}
+
+#[tauri::command]
+fn save_settings(
+    app_handle: tauri::AppHandle,
+    window: tauri::Window,
+    settings: Settings,
+) -> Result<(), String> {
+    settings.validate_shortcuts()?;
+    write_settings(settings.clone())?;
+    register_shortcuts(&app_handle, &settings);
+    // Hidden rather than closed, so reopening it is instant
+    if settings.auto_close_settings_on_save {
+        if let Err(e) = window.hide() {
+            eprintln!("Failed to hide the settings window: {}", e);
+        }
+    }
+    Ok(())
+}
+
+// Re-reads settings.json, e.g. after it was edited by hand or synced from
+// another machine, and re-applies the shortcuts
+#[tauri::command]
+fn reload_settings(app_handle: tauri::AppHandle) -> Settings {
+    *SETTINGS_CACHE.write().unwrap() = None;
+    let settings = load_settings();
+    register_shortcuts(&app_handle, &settings);
+    if let Err(e) = app_handle.emit_all("settings:reloaded", &settings) {
+        eprintln!("Failed to emit settings:reloaded: {}", e);
+    }
+    settings
+}
+
+// Full settings as JSON for moving to another machine. Without
+// `include_api_keys` the keys are left out so the config can be shared.
+#[tauri::command]
+fn export_settings(include_api_keys: bool) -> Result<String, String> {
+    let mut settings = load_settings();
+    if !include_api_keys {
+        settings.api_keys.clear();
+    }
+    serde_json::to_string_pretty(&settings)
+        .map_err(|e| format!("Failed to serialize settings: {}", e))
+}
+
+// Replaces the settings with exported JSON. Fields missing from older exports
+// get their defaults, and API keys not in the export are kept.
+#[tauri::command]
+fn import_settings(app_handle: tauri::AppHandle, json: String) -> Result<Settings, String> {
+    let imported: serde_json::Value =
+        serde_json::from_str(&json).map_err(|e| format!("Invalid settings JSON: {}", e))?;
+    let serde_json::Value::Object(imported) = imported else {
+        return Err("Invalid settings JSON: expected an object".to_string());
+    };
+
+    let mut merged = serde_json::to_value(Settings::default())
+        .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+    if let serde_json::Value::Object(fields) = &mut merged {
+        // An export without a version predates versioning
+        fields.insert("schema_version".to_string(), 0.into());
+        fields.extend(imported);
+    }
+    let mut settings: Settings =
+        serde_json::from_value(merged).map_err(|e| format!("Invalid settings: {}", e))?;
+    settings.migrate();
+    settings.validate_shortcuts()?;
+
+    for (provider, api_key) in load_settings().api_keys {
+        settings.api_keys.entry(provider).or_insert(api_key);
+    }
+
+    write_settings(settings)?;
+    Ok(reload_settings(app_handle))
+}
+
+// Polls the settings file and reloads whenever its modification time changes
+fn watch_settings_file(app_handle: tauri::AppHandle) {
+    let modified = || {
+        fs::metadata(get_settings_path())
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    };
+    tauri::async_runtime::spawn(async move {
+        let mut last_modified = modified();
+        loop {
+            tokio::time::sleep(SETTINGS_WATCH_INTERVAL).await;
+            let current = modified();
+            if current != last_modified {
+                last_modified = current;
+                reload_settings(app_handle.clone());
+            }
+        }
+    });
+}
+
+fn get_instance_lock_path() -> PathBuf {
+    get_settings_path().with_file_name("instance.lock")
+}
+
+// The port the running instance listens on, if this process holds the lock
+struct InstanceLock(Option<u16>);
+
+// Takes the lock for this process, or returns None after asking the instance
+// that holds it to show its settings window. The lock file holds that
+// instance's port; it is created with a hard link so another launch never
+// reads it half-written.
+fn acquire_instance_lock() -> Result<Option<TcpListener>, String> {
+    let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .map_err(|e| format!("Failed to open the instance port: {}", e))?;
+    let port = listener
+        .local_addr()
+        .map_err(|e| format!("Failed to read the instance port: {}", e))?
+        .port();
+    let lock_path = get_instance_lock_path();
+    let staged_path = lock_path.with_extension(format!("lock.{}", std::process::id()));
+    fs::write(&staged_path, port.to_string())
+        .map_err(|e| format!("Failed to write {}: {}", staged_path.display(), e))?;
+
+    let mut result = Err(format!("Failed to take {}", lock_path.display()));
+    for _ in 0..INSTANCE_LOCK_ATTEMPTS {
+        match fs::hard_link(&staged_path, &lock_path) {
+            Ok(()) => {
+                result = Ok(Some(listener));
+                break;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                // Gone again, so try to take it
+                let Ok(content) = fs::read_to_string(&lock_path) else {
+                    continue;
+                };
+                let port = content.trim().parse::<u16>().ok();
+                if port.is_some_and(signal_running_instance) {
+                    result = Ok(None);
+                    break;
+                }
+                // Left behind by a crash, unless another launch has replaced
+                // it with its own lock in the meantime
+                if fs::read_to_string(&lock_path).is_ok_and(|current| current == content) {
+                    eprintln!("Removing stale {}", lock_path.display());
+                    let _ = fs::remove_file(&lock_path);
+                }
+            }
+            Err(e) => {
+                result = Err(format!("Failed to create {}: {}", lock_path.display(), e));
+                break;
+            }
+        }
+    }
+    let _ = fs::remove_file(&staged_path);
+    result
+}
+
+// Asks the instance listening on `port` to show its settings window. False when
+// nothing accepts the connection, so the lock is stale. The port is bound
+// before the lock is taken but only served once the app has started, so an
+// instance that accepts without answering is still running.
+fn signal_running_instance(port: u16) -> bool {
+    let address = SocketAddr::from((Ipv4Addr::LOCALHOST, port));
+    let Ok(mut stream) = TcpStream::connect_timeout(&address, INSTANCE_TIMEOUT) else {
+        return false;
+    };
+    let _ = stream.set_read_timeout(Some(INSTANCE_TIMEOUT));
+    let mut reply = String::new();
+    let answered = writeln!(stream, "{}", INSTANCE_REQUEST).is_ok()
+        && BufReader::new(stream).read_line(&mut reply).is_ok()
+        && reply.trim() == INSTANCE_REPLY;
+    if !answered {
+        eprintln!("The running instance hasn't answered yet, it may still be starting");
+    }
+    true
+}
+
+// Opens the settings window for each later launch, which then exits
+fn listen_for_instances(app_handle: tauri::AppHandle, listener: TcpListener) {
+    std::thread::spawn(move || {
+        for mut stream in listener.incoming().flatten() {
+            let _ = stream.set_read_timeout(Some(INSTANCE_TIMEOUT));
+            let mut request = String::new();
+            if BufReader::new(&stream).read_line(&mut request).is_err()
+                || request.trim() != INSTANCE_REQUEST
+            {
+                continue;
+            }
+            let _ = writeln!(stream, "{}", INSTANCE_REPLY);
+            open_settings_window(&app_handle);
+        }
+    });
+}
+
+// Only removes the lock if it is still this process's
+fn release_instance_lock(lock: &InstanceLock) {
+    let Some(port) = lock.0 else {
+        return;
+    };
+    let lock_path = get_instance_lock_path();
+    if fs::read_to_string(&lock_path).is_ok_and(|content| content.trim() == port.to_string()) {
+        let _ = fs::remove_file(&lock_path);
+    }
+}
+
+fn write_settings(mut settings: Settings) -> Result<(), String> {
+    // Ensure legacy fields are cleared
+    settings.api_key = None;
+    settings.base_url = None;
+
+    let settings_path = get_settings_path();
+    let json = serde_json::to_string_pretty(&settings)
+        .map_err(|e| format!("Failed to serialize settings: {}", e))?;
+
+    // Held across the write so concurrent saves land in the same order in
+    // the file and the cache
+    let mut cache = SETTINGS_CACHE.write().unwrap();
+    fs::write(settings_path, json).map_err(|e| format!("Failed to write settings: {}", e))?;
+    *cache = Some(settings);
+
+    Ok(())
+}
+
+#[tauri::command]
+fn get_api_key_for_provider(provider: String) -> String {
+    let settings = load_settings();
+    settings
+        .api_keys
+        .get(&provider)
+        .cloned()
+        .unwrap_or_default()
+}
+
+#[tauri::command]
+fn save_api_key_for_provider(provider: String, api_key: String) -> Result<(), String> {
+    let mut settings = load_settings();
+    settings.set_api_key(&provider, &api_key);
+    write_settings(settings)
+}
+
+#[tauri::command]
+fn delete_api_key_for_provider(provider: String) -> Result<(), String> {
+    let mut settings = load_settings();
+    if settings.api_keys.remove(&provider).is_none() {
+        return Ok(());
+    }
+    write_settings(settings)
+}
+
+#[derive(Serialize)]
+struct ProviderModel {
+    value: &'static str,
+    label: &'static str,
+}
+
+// What the settings window needs to offer a provider
+#[derive(Serialize)]
+struct ProviderInfo {
+    id: &'static str,
+    name: &'static str,
+    requires_api_key: bool,
+    default_base_url: &'static str,
+    default_model: &'static str,
+    models: Vec<ProviderModel>,
+}
+
+// Which settings a provider honors, so the settings window can hide the rest
+#[derive(Serialize)]
+struct Capabilities {
+    temperature: bool,
+    top_p: bool,
+    json_output: bool,       // Used by structured translation
+    streaming: bool,         // Into the preview window
+    max_tokens: Option<u32>, // Highest output limit among the known models
+}
+
+#[tauri::command]
+fn provider_capabilities(provider: String) -> Result<Capabilities, String> {
+    let entry = provider_registry().get(&provider)?;
+    let ProviderFeatures {
+        temperature,
+        top_p,
+        json_output,
+        streaming,
+    } = entry.features;
+    let max_tokens = entry
+        .models
+        .iter()
+        .filter_map(|(model, _)| builtin_model_max_tokens(model))
+        .max();
+    Ok(Capabilities {
+        temperature,
+        top_p,
+        json_output,
+        streaming,
+        max_tokens,
+    })
+}
+
+// Listed in registration order, which is the order the settings window shows
+#[tauri::command]
+fn supported_providers() -> Vec<ProviderInfo> {
+    provider_registry()
+        .entries()
+        .map(|entry| ProviderInfo {
+            id: entry.id,
+            name: entry.name,
+            requires_api_key: true,
+            default_base_url: entry.default_base_url,
+            default_model: entry.default_model,
+            models: entry
+                .models
+                .iter()
+                .map(|&(value, label)| ProviderModel { value, label })
+                .collect(),
+        })
+        .collect()
+}
+
+// Providers with a key, stored or from the environment
+#[tauri::command]
+fn list_configured_providers() -> Vec<String> {
+    let settings = load_settings();
+    let mut providers: Vec<String> = settings
+        .api_keys
+        .iter()
+        .filter(|(_, api_key)| !api_key.is_empty())
+        .map(|(provider, _)| provider.clone())
+        .collect();
+    for provider in provider_registry().entries().map(|entry| entry.id) {
+        let from_env = std::env::var(api_key_env_var(provider))
+            .is_ok_and(|api_key| !api_key.trim().is_empty());
+        if from_env && !providers.iter().any(|configured| configured == provider) {
+            providers.push(provider.to_string());
+        }
+    }
+    providers.sort();
+    providers
+}
+
+#[tauri::command]
+fn add_few_shot_example(input: String, output: String) -> Result<Vec<(String, String)>, String> {
+    if input.trim().is_empty() || output.trim().is_empty() {
+        return Err("Both the example input and output are required".to_string());
+    }
+
+    let mut settings = load_settings();
+    if settings.few_shot_examples.len() >= MAX_FEW_SHOT_EXAMPLES {
+        return Err(format!(
+            "At most {} examples are supported",
+            MAX_FEW_SHOT_EXAMPLES
+        ));
+    }
+    settings
+        .few_shot_examples
+        .push((input.trim().to_string(), output.trim().to_string()));
+    let examples = settings.few_shot_examples.clone();
+    write_settings(settings)?;
+    Ok(examples)
+}
+
+#[tauri::command]
+fn remove_few_shot_example(index: usize) -> Result<Vec<(String, String)>, String> {
+    let mut settings = load_settings();
+    if index >= settings.few_shot_examples.len() {
+        return Err(format!("No example at index {}", index));
+    }
+    settings.few_shot_examples.remove(index);
+    let examples = settings.few_shot_examples.clone();
+    write_settings(settings)?;
+    Ok(examples)
+}
+
+// Parsed settings, so shortcut presses don't re-read the file. Kept up to
+// date by write_settings and cleared when the file is reloaded from disk.
+static SETTINGS_CACHE: RwLock<Option<Settings>> = RwLock::new(None);
+
+#[tauri::command]
+fn load_settings() -> Settings {
+    if let Some(settings) = SETTINGS_CACHE.read().unwrap().as_ref() {
+        return settings.clone();
+    }
+
+    let mut cache = SETTINGS_CACHE.write().unwrap();
+    let settings = cache.get_or_insert_with(read_settings_file);
+    settings.clone()
+}
+
+fn read_settings_file() -> Settings {
+    let settings_path = get_settings_path();
+
+    if let Ok(content) = fs::read_to_string(settings_path) {
+        parse_settings(&content)
+    } else {
+        Settings::default()
+    }
+}
+
+// Unreadable files fall back to the defaults
+fn parse_settings(content: &str) -> Settings {
+    let mut settings: Settings = serde_json::from_str(content).unwrap_or_default();
+    settings.migrate();
+    settings
+}
+
+// What to ask the model for, independent of each provider's wire format
+#[derive(Clone)]
+struct PromptSpec<'a> {
+    prompt: String,
+    temperature: f32,
+    examples: &'a [(String, String)], // (input, output) pairs shown before the text
+    json_output: bool,
+    max_tokens: Option<u32>, // Lower than the configured limit for short answers
+    code: bool,              // The answer is code, so it is kept verbatim apart from fences
+}
+
+impl PromptSpec<'_> {
+    fn max_tokens(&self, settings: &Settings) -> u32 {
+        let limit = settings.effective_max_tokens();
+        self.max_tokens
+            .map_or(limit, |max_tokens| max_tokens.min(limit))
+    }
+}
+
+// English name of the input's language, when it can be told reliably
+fn detect_language(text: &str) -> Option<&'static str> {
+    whatlang::detect(text)
+        .filter(|info| info.is_reliable())
+        .map(|info| info.lang().eng_name())
+}
+
+// Turns "en_GB.UTF-8" or "en-GB" into "en-GB". Only tags with both a language
+// and a region say which spelling conventions apply.
+fn normalize_locale(locale: &str) -> Option<String> {
+    let tag = locale.trim().split(['.', '@']).next()?;
+    let (language, region) = tag.split_once(['_', '-'])?;
+    let valid = (2..=3).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && region.len() == 2
+        && region.chars().all(|c| c.is_ascii_alphabetic());
+    valid.then(|| format!("{}-{}", language.to_lowercase(), region.to_uppercase()))
+}
+
+// Apps started from the Finder get no LANG, so ask the user defaults first.
+// That spawns a process, so it's only done once per run.
+#[cfg(target_os = "macos")]
+fn system_locale() -> Option<String> {
+    static LOCALE: OnceLock<Option<String>> = OnceLock::new();
+    LOCALE
+        .get_or_init(|| {
+            std::process::Command::new("defaults")
+                .args(["read", "-g", "AppleLocale"])
+                .output()
+                .ok()
+                .filter(|output| output.status.success())
+                .and_then(|output| normalize_locale(&String::from_utf8_lossy(&output.stdout)))
+                .or_else(locale_from_env)
+        })
+        .clone()
+}
+
+#[cfg(target_os = "windows")]
+fn system_locale() -> Option<String> {
+    use windows_sys::Win32::Globalization::GetUserDefaultLocaleName;
+
+    const LOCALE_NAME_MAX_LENGTH: usize = 85;
+    let mut name = [0u16; LOCALE_NAME_MAX_LENGTH];
+    let length = unsafe { GetUserDefaultLocaleName(name.as_mut_ptr(), name.len() as i32) };
+    if length <= 1 {
+        return None;
+    }
+    // The length includes the terminating null
+    normalize_locale(&String::from_utf16_lossy(&name[..length as usize - 1]))
+}
+
+#[cfg(not(any(target_os = "macos", target_os = "windows")))]
+fn system_locale() -> Option<String> {
+    locale_from_env()
+}
+
+// The first of the POSIX locale variables that is set, in precedence order
+#[cfg_attr(target_os = "windows", allow(dead_code))]
+fn locale_from_env() -> Option<String> {
+    ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|value| !value.is_empty())
+        .and_then(|value| normalize_locale(&value))
+}
+
+// `locale_override` if set, else the system locale with `use_system_locale`
+fn preferred_locale(settings: &Settings) -> Option<String> {
+    let locale_override = settings.locale_override.trim();
+    if !locale_override.is_empty() {
+        return normalize_locale(locale_override).or_else(|| Some(locale_override.to_string()));
+    }
+    settings.use_system_locale.then(system_locale).flatten()
+}
+
+impl TextAction {
+    fn prompt_spec<'a>(self, settings: &'a Settings, text: &str) -> PromptSpec<'a> {
+        let mut spec = match self {
+            TextAction::Polish => {
+                // English-centric prompts can make the model translate instead
+                let language = settings
+                    .preserve_language_on_polish
+                    .then(|| detect_language(text))
+                    .flatten();
+                let prompt = match language {
+                    Some(language) => format!(
+                        "Respond in the same language as the input ({}).\n\n{}",
+                        language, settings.prompt
+                    ),
+                    None => settings.prompt.clone(),
+                };
+                PromptSpec {
+                    prompt,
+                    temperature: 0.3,
+                    examples: settings.few_shot_examples(),
+                    json_output: false,
+                    max_tokens: None,
+                    code: false,
+                }
+            }
+            TextAction::Translate => {
+                let structured =
+                    settings.structured_translation
+                        && provider_registry()
+                            .get(&settings.provider)
+                            .is_ok_and(|entry| entry.features.json_output);
+                let prompt = if structured {
+                    "Translate the following text to English. If the text is already in English, keep it as is. Respond with a JSON object with the keys \"translation\" (the translated text), \"detected_language\" (the language of the input) and \"confidence\" (a number from 0 to 1 describing how confident you are in the translation)."
+                } else {
+                    "Translate the following text to English. If the text is already in English, keep it as is. Only return the translated text without any additional explanation:"
+                };
+                PromptSpec {
+                    prompt: prompt.to_string(),
+                    temperature: 0.1, // Lower temperature for more consistent translations
+                    examples: &[],
+                    json_output: structured,
+                    max_tokens: None,
+                    code: false,
+                }
+            }
+            TextAction::Proofread => PromptSpec {
+                prompt: settings.proofread_prompt.clone(),
+                temperature: settings.proofread_temperature,
+                examples: &[],
+                json_output: false,
+                max_tokens: None,
+                code: false,
+            },
+            TextAction::Summarize => PromptSpec {
+                prompt: "Summarize the following text concisely, keeping its key points. Only return the summary:".to_string(),
+                temperature: 0.3,
+                examples: &[],
+                json_output: false,
+                max_tokens: None,
+                code: false,
+            },
+            TextAction::CommitMessage => PromptSpec {
+                prompt: settings.commit_prompt.clone(),
+                temperature: 0.2,
+                examples: &[],
+                json_output: false,
+                max_tokens: Some(COMMIT_MESSAGE_MAX_TOKENS),
+                code: false,
+            },
+            TextAction::CodePolish => PromptSpec {
+                prompt: CODE_POLISH_PROMPT.to_string(),
+                temperature: 0.2,
+                examples: &[],
+                json_output: false,
+                max_tokens: None,
+                code: true,
+            },
+            TextAction::ImproveAndExplain => PromptSpec {
+                prompt: format!(
+                    "{}\n\nAfter the improved text, write a line containing only {} followed by a short bullet list of what you changed and why.",
+                    settings.prompt, EXPLANATION_DELIMITER
+                ),
+                temperature: 0.3,
+                examples: &[],
+                json_output: false,
+                max_tokens: None,
+                code: false,
+            },
+            // The binding's prompt replaces this. Offline actions never send one.
+            TextAction::Custom
+            | TextAction::Tidy
+            | TextAction::Base64Encode
+            | TextAction::Base64Decode
+            | TextAction::UrlEncode
+            | TextAction::UrlDecode
+            | TextAction::ConvertCase
+            | TextAction::StripMarkdown => PromptSpec {
+                prompt: settings.prompt.clone(),
+                temperature: 0.3,
+                examples: &[],
+                json_output: false,
+                max_tokens: None,
+                code: false,
+            },
+        };
+
+        if let Some(temperature) = settings
+            .overrides_for(self)
+            .and_then(|overrides| overrides.temperature)
+        {
+            spec.temperature = temperature;
+        }
+
+        // These go before the prompt, which usually ends by introducing the text
+        let style_guide = settings.style_guide.trim();
+        if self == TextAction::Polish && !style_guide.is_empty() {
+            spec.prompt = format!("Style guide: {}\n\n{}", style_guide, spec.prompt);
+        }
+        if self == TextAction::Polish {
+            if let Some(locale) = preferred_locale(settings) {
+                spec.prompt = format!(
+                    "Follow {} spelling and conventions for text in that language.\n\n{}",
+                    locale, spec.prompt
+                );
+            }
+        }
+        if self == TextAction::Translate {
+            if let Some(glossary) = glossary_instructions(settings) {
+                spec.prompt = format!("{}\n\n{}", glossary, spec.prompt);
+            }
+        }
+        let context = settings.context.trim();
+        if self.edits_in_place() && settings.use_context && !context.is_empty() {
+            spec.prompt = format!(
+                "Context (take it into account, but do not rewrite it or include it in your answer):\n{}\n\n{}",
+                context, spec.prompt
+            );
+        }
+        spec
+    }
+}
+
+// Receives partial output while a response is streamed
+type ChunkCallback<'a> = &'a (dyn Fn(&str) + Sync);
+
+// Raw provider output for a request, before post-processing, so changing
+// cleanup settings doesn't need a new request
+#[derive(Serialize, Deserialize, Clone)]
+struct CachedResult {
+    key: String, // Hex SHA-256, stable across builds unlike std's hashers
+    text: String,
+    stored_at: u64, // Unix seconds
+}
+
+// Most recently used last. None until first used, so cache.json is read lazily.
+static RESULT_CACHE: Mutex<Option<Vec<CachedResult>>> = Mutex::new(None);
+
+fn get_cache_path() -> PathBuf {
+    get_settings_path().with_file_name("cache.json")
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+// Everything that changes what the provider would return
+fn result_cache_key(text: &str, spec: &PromptSpec<'_>, settings: &Settings) -> String {
+    use std::fmt::Write as _;
+    // Sorted, so the key doesn't depend on HashMap order
+    let extra_headers: BTreeMap<_, _> = settings
+        .extra_headers
+        .get(&settings.provider)
+        .into_iter()
+        .flatten()
+        .collect();
+    let fields = serde_json::json!([
+        settings.provider,
+        settings.model,
+        settings.get_current_base_url(),
+        spec.prompt,
+        spec.temperature.to_bits(),
+        spec.examples,
+        spec.json_output,
+        spec.max_tokens(settings),
+        settings.top_p.map(f32::to_bits),
+        settings.extra_body.get(&settings.provider),
+        extra_headers,
+        text,
+    ]);
+    Sha256::digest(fields.to_string())
+        .iter()
+        .fold(String::new(), |mut key, byte| {
+            let _ = write!(key, "{:02x}", byte);
+            key
+        })
+}
+
+fn with_result_cache<T>(settings: &Settings, f: impl FnOnce(&mut Vec<CachedResult>) -> T) -> T {
+    let mut cache = RESULT_CACHE.lock().unwrap();
+    let entries = cache.get_or_insert_with(|| {
+        if !settings.persist_cache {
+            return Vec::new();
+        }
+        fs::read_to_string(get_cache_path())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    });
+    let now = unix_now();
+    entries.retain(|entry| now.saturating_sub(entry.stored_at) < settings.cache_ttl_secs);
+    f(entries)
+}
+
+fn get_cached_result(key: &str, settings: &Settings) -> Option<LlmOutput> {
+    with_result_cache(settings, |entries| {
+        let index = entries.iter().position(|entry| entry.key == key)?;
+        let entry = entries.remove(index);
+        let output = LlmOutput {
+            text: entry.text.clone(),
+            truncated: false,
+            translation_details: None,
+            cached: true,
+            fallback_provider: None,
+            usage: None,
+            reduced_max_tokens: None,
+        };
+        entries.push(entry);
+        Some(output)
+    })
+}
+
+fn store_cached_result(key: String, output: &LlmOutput, settings: &Settings) {
+    with_result_cache(settings, |entries| {
+        entries.retain(|entry| entry.key != key);
+        entries.push(CachedResult {
+            key,
+            text: output.text.clone(),
+            stored_at: unix_now(),
+        });
+        let excess = entries.len().saturating_sub(settings.cache_size);
+        entries.drain(..excess);
+
+        if settings.persist_cache {
+            let saved = serde_json::to_string(entries)
+                .map_err(|e| e.to_string())
+                .and_then(|json| fs::write(get_cache_path(), json).map_err(|e| e.to_string()));
+            if let Err(e) = saved {
+                eprintln!("Failed to save result cache: {}", e);
+            }
+        }
+    });
+}
+
+// Everything a provider needs for one request
+#[derive(Clone, Copy)]
+struct LlmRequest<'a> {
+    text: &'a str,
+    spec: &'a PromptSpec<'a>,
+    settings: &'a Settings,
+    client: &'a reqwest::Client,
+}
+
+// A provider's API. `stream` passes the answer to `on_chunk` as it arrives;
+// providers without a streaming API keep the default, which delivers the whole
+// answer as one chunk once it is complete.
+trait LlmProvider: Send + Sync {
+    fn complete<'a>(&'a self, request: LlmRequest<'a>) -> BoxFuture<'a, Result<LlmOutput, String>>;
+
+    // The URL, Authorization header and body `complete` would send
+    fn prepare(
+        &self,
+        text: &str,
+        spec: &PromptSpec<'_>,
+        settings: &Settings,
+    ) -> Result<(String, Option<String>, serde_json::Value), String>;
+
+    fn stream<'a>(
+        &'a self,
+        request: LlmRequest<'a>,
+        on_chunk: ChunkCallback<'a>,
+    ) -> BoxFuture<'a, Result<LlmOutput, String>> {
+        Box::pin(async move {
+            let output = self.complete(request).await?;
+            on_chunk(&output.text);
+            Ok(output)
+        })
+    }
+}
+
+// OpenAI and the providers with an OpenAI-compatible API
+struct OpenAiProvider;
+
+impl LlmProvider for OpenAiProvider {
+    fn complete<'a>(&'a self, request: LlmRequest<'a>) -> BoxFuture<'a, Result<LlmOutput, String>> {
+        let LlmRequest {
+            text,
+            spec,
+            settings,
+            client,
+        } = request;
+        Box::pin(complete_with_openai(text, spec, settings, client, None))
+    }
+
+    fn prepare(
+        &self,
+        text: &str,
+        spec: &PromptSpec<'_>,
+        settings: &Settings,
+    ) -> Result<(String, Option<String>, serde_json::Value), String> {
+        Ok((
+            openai_url(settings),
+            Some(format!("Bearer {}", settings.get_current_api_key())),
+            build_openai_request(text, spec, settings, false)?,
+        ))
+    }
+
+    fn stream<'a>(
+        &'a self,
+        request: LlmRequest<'a>,
+        on_chunk: ChunkCallback<'a>,
+    ) -> BoxFuture<'a, Result<LlmOutput, String>> {
+        let LlmRequest {
+            text,
+            spec,
+            settings,
+            client,
+        } = request;
+        Box::pin(complete_with_openai(
+            text,
+            spec,
+            settings,
+            client,
+            Some(on_chunk),
+        ))
+    }
+}
+
+struct GeminiProvider;
+
+impl LlmProvider for GeminiProvider {
+    fn complete<'a>(&'a self, request: LlmRequest<'a>) -> BoxFuture<'a, Result<LlmOutput, String>> {
+        let LlmRequest {
+            text,
+            spec,
+            settings,
+            client,
+        } = request;
+        Box::pin(complete_with_gemini(text, spec, settings, client))
+    }
+
+    // The key goes in the URL instead of a header
+    fn prepare(
+        &self,
+        text: &str,
+        spec: &PromptSpec<'_>,
+        settings: &Settings,
+    ) -> Result<(String, Option<String>, serde_json::Value), String> {
+        Ok((
+            gemini_url(settings, &settings.get_current_api_key()),
+            None,
+            build_gemini_request(text, spec, settings)?,
+        ))
+    }
+}
+
+struct CohereProvider;
+
+impl LlmProvider for CohereProvider {
+    fn complete<'a>(&'a self, request: LlmRequest<'a>) -> BoxFuture<'a, Result<LlmOutput, String>> {
+        let LlmRequest {
+            text,
+            spec,
+            settings,
+            client,
+        } = request;
+        Box::pin(complete_with_cohere(text, spec, settings, client))
+    }
+
+    fn prepare(
+        &self,
+        text: &str,
+        spec: &PromptSpec<'_>,
+        settings: &Settings,
+    ) -> Result<(String, Option<String>, serde_json::Value), String> {
+        Ok((
+            cohere_url(settings),
+            Some(format!("Bearer {}", settings.get_current_api_key())),
+            build_cohere_request(text, spec, settings)?,
         ))
-        .add_native_item(tauri::SystemTrayMenuItem::Separator)
-        .add_item(tauri::CustomMenuItem::new("quit".to_string(), "Quit"));
-    let system_tray = SystemTray::new().with_menu(tray_menu);
+    }
+}
+
+// Which settings a provider's request builder honors
+#[derive(Clone, Copy)]
+struct ProviderFeatures {
+    temperature: bool,
+    top_p: bool,
+    json_output: bool, // Perplexity rejects the json_object response format
+    streaming: bool,
+}
+
+const OPENAI_FEATURES: ProviderFeatures = ProviderFeatures {
+    temperature: true,
+    top_p: true,
+    json_output: true,
+    streaming: true,
+};
+
+// Everything the app knows about one provider
+struct ProviderEntry {
+    id: &'static str,
+    name: &'static str,
+    default_base_url: &'static str,
+    default_model: &'static str,
+    // Offered in the settings window; any other model name can still be set
+    // in settings.json
+    models: &'static [(&'static str, &'static str)],
+    features: ProviderFeatures,
+    api: Box<dyn LlmProvider>,
+}
+
+// The providers, in the order the settings window lists them. A new provider
+// only needs to be registered here; OpenAI-compatible ones reuse
+// OpenAiProvider.
+struct ProviderRegistry(Vec<ProviderEntry>);
+
+impl ProviderRegistry {
+    fn new() -> Self {
+        let mut registry = ProviderRegistry(Vec::new());
+        registry.register(ProviderEntry {
+            id: "openai",
+            name: "OpenAI",
+            default_base_url: "https://api.openai.com/v1",
+            default_model: "gpt-3.5-turbo",
+            models: &[
+                ("gpt-3.5-turbo", "GPT-3.5 Turbo"),
+                ("gpt-4", "GPT-4"),
+                ("gpt-4-turbo", "GPT-4 Turbo"),
+                ("gpt-4o", "GPT-4o"),
+                ("o1", "o1"),
+                ("o3-mini", "o3-mini"),
+            ],
+            features: OPENAI_FEATURES,
+            api: Box::new(OpenAiProvider),
+        });
+        registry.register(ProviderEntry {
+            id: "gemini",
+            name: "Google Gemini",
+            default_base_url: "https://generativelanguage.googleapis.com",
+            default_model: "gemini-flash-latest",
+            models: &[
+                ("gemini-flash-latest", "Gemini Latest Flash"),
+                ("gemini-pro-latest", "Gemini Latest Pro"),
+                ("gemini-pro", "Gemini Pro"),
+            ],
+            features: ProviderFeatures {
+                json_output: false,
+                streaming: false,
+                ..OPENAI_FEATURES
+            },
+            api: Box::new(GeminiProvider),
+        });
+        registry.register(ProviderEntry {
+            id: "cohere",
+            name: "Cohere",
+            default_base_url: "https://api.cohere.ai",
+            default_model: "command-r-plus",
+            models: &[
+                ("command-r-plus", "Command R+"),
+                ("command-r", "Command R"),
+                ("command", "Command"),
+            ],
+            features: ProviderFeatures {
+                json_output: false,
+                streaming: false,
+                ..OPENAI_FEATURES
+            },
+            api: Box::new(CohereProvider),
+        });
+        // Perplexity and Together are OpenAI-compatible and only differ in their defaults
+        registry.register(ProviderEntry {
+            id: "perplexity",
+            name: "Perplexity",
+            default_base_url: "https://api.perplexity.ai",
+            default_model: "sonar",
+            models: &[
+                ("sonar", "Sonar"),
+                ("sonar-pro", "Sonar Pro"),
+                ("sonar-reasoning", "Sonar Reasoning"),
+            ],
+            features: ProviderFeatures {
+                json_output: false,
+                ..OPENAI_FEATURES
+            },
+            api: Box::new(OpenAiProvider),
+        });
+        registry.register(ProviderEntry {
+            id: "together",
+            name: "Together AI",
+            default_base_url: "https://api.together.xyz/v1",
+            default_model: "meta-llama/Llama-3.3-70B-Instruct-Turbo",
+            models: &[
+                (
+                    "meta-llama/Llama-3.3-70B-Instruct-Turbo",
+                    "Llama 3.3 70B Instruct Turbo",
+                ),
+                (
+                    "Qwen/Qwen2.5-72B-Instruct-Turbo",
+                    "Qwen 2.5 72B Instruct Turbo",
+                ),
+                (
+                    "mistralai/Mixtral-8x7B-Instruct-v0.1",
+                    "Mixtral 8x7B Instruct",
+                ),
+            ],
+            features: OPENAI_FEATURES,
+            api: Box::new(OpenAiProvider),
+        });
+        registry
+    }
+
+    // Replaces an entry with the same id
+    fn register(&mut self, entry: ProviderEntry) {
+        self.0.retain(|existing| existing.id != entry.id);
+        self.0.push(entry);
+    }
+
+    fn get(&self, id: &str) -> Result<&ProviderEntry, String> {
+        self.0
+            .iter()
+            .find(|entry| entry.id == id)
+            .ok_or_else(|| format!("Unknown provider: {}", id))
+    }
+
+    fn entries(&self) -> impl Iterator<Item = &ProviderEntry> {
+        self.0.iter()
+    }
+
+    // Empty for unknown providers, which fail when a request is made
+    fn default_model(&self, id: &str) -> &'static str {
+        self.get(id).map_or("", |entry| entry.default_model)
+    }
+}
+
+fn provider_registry() -> &'static ProviderRegistry {
+    static REGISTRY: OnceLock<ProviderRegistry> = OnceLock::new();
+    REGISTRY.get_or_init(ProviderRegistry::new)
+}
+
+// One provider request, retrying empty answers
+async fn complete_with_provider(
+    registry: &ProviderRegistry,
+    text: &str,
+    spec: &PromptSpec<'_>,
+    settings: &Settings,
+    client: &reqwest::Client,
+    on_chunk: Option<ChunkCallback<'_>>,
+) -> Result<LlmOutput, String> {
+    let mut attempt = 0;
+    let mut reduced_spec: Option<PromptSpec> = None;
+    let provider = registry.get(&settings.provider)?.api.as_ref();
+    let max_tokens = settings.effective_max_tokens();
+    if max_tokens < settings.max_tokens {
+        eprintln!(
+            "max_tokens {} exceeds the {} limit for {}, clamping",
+            settings.max_tokens, max_tokens, settings.model
+        );
+    }
+    loop {
+        let current = reduced_spec.as_ref().unwrap_or(spec);
+        let request = LlmRequest {
+            text,
+            spec: current,
+            settings,
+            client,
+        };
+        let result = match on_chunk {
+            Some(on_chunk) => provider.stream(request, on_chunk).await,
+            None => provider.complete(request).await,
+        };
+        match result {
+            Err(e) if is_empty_response_error(&e) && attempt < EMPTY_RESPONSE_RETRIES => {
+                attempt += 1;
+                eprintln!("{}, retrying", e);
+            }
+            // Leave more of the window for the input, once
+            Err(e) if is_context_length_error(&e) && reduced_spec.is_none() => {
+                let max_tokens = current.max_tokens(settings) / 2;
+                eprintln!("{}, retrying with max_tokens {}", e, max_tokens);
+                reduced_spec = Some(PromptSpec {
+                    max_tokens: Some(max_tokens),
+                    ..current.clone()
+                });
+            }
+            result => {
+                return result.map(|output| LlmOutput {
+                    reduced_max_tokens: reduced_spec.and_then(|spec| spec.max_tokens),
+                    ..output
+                })
+            }
+        }
+    }
+}
+
+// A client with the provider's timeout. It covers the whole request, including
+// reading a streamed response.
+fn http_client(settings: &Settings) -> reqwest::Client {
+    let mut builder = reqwest::Client::builder();
+    if let Some(timeout) = settings.request_timeout() {
+        builder = builder.timeout(timeout);
+    }
+    builder.build().unwrap_or_else(|e| {
+        eprintln!("Failed to build HTTP client, using defaults: {}", e);
+        reqwest::Client::new()
+    })
+}
+
+// Outages rather than problems with the request, which another provider would share
+fn is_failover_error(error: &str) -> bool {
+    error.starts_with("Request failed") || error.contains("status: 5")
+}
+
+// Failures that may well go away if the same request is sent again later
+fn is_retryable_error(error: &str) -> bool {
+    is_failover_error(error) || error.contains("status: 429")
+}
+
+// Stands in for the nth masked value in the text sent to the model
+fn redaction_placeholder(index: usize) -> String {
+    format!("[REDACTED_{}]", index)
+}
+
+// Models sometimes change a placeholder's brackets, case or separator
+const LENIENT_PLACEHOLDER_PATTERN: &str = r"(?i)\[*REDACTED[_ -]?(\d+)\]*";
+
+fn redaction_patterns(settings: &Settings) -> Result<Vec<Regex>, String> {
+    settings
+        .redact_patterns
+        .iter()
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| {
+            Regex::new(pattern).map_err(|e| format!("Invalid redact pattern {}: {}", pattern, e))
+        })
+        .collect()
+}
+
+// Replaces every match of `patterns` with a numbered placeholder. Returns the
+// masked text and the originals, where placeholder n stands for the (n-1)th.
+// Overlapping matches keep the one that starts first, and repeated values
+// share a placeholder.
+fn redact(text: &str, patterns: &[Regex]) -> (String, Vec<String>) {
+    let mut matches: Vec<(usize, usize)> = patterns
+        .iter()
+        .flat_map(|pattern| pattern.find_iter(text))
+        .filter(|found| !found.is_empty())
+        .map(|found| (found.start(), found.end()))
+        .collect();
+    matches.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+
+    let mut masked = String::with_capacity(text.len());
+    let mut originals: Vec<String> = Vec::new();
+    let mut position = 0;
+    for (start, end) in matches {
+        if start < position {
+            continue;
+        }
+        let value = &text[start..end];
+        let index = match originals.iter().position(|original| original == value) {
+            Some(index) => index,
+            None => {
+                originals.push(value.to_string());
+                originals.len() - 1
+            }
+        };
+        masked.push_str(&text[position..start]);
+        masked.push_str(&redaction_placeholder(index + 1));
+        position = end;
+    }
+    masked.push_str(&text[position..]);
+    (masked, originals)
+}
+
+// Puts the originals back. Returns the restored text and how many of the
+// placeholders the model dropped.
+fn unredact(text: &str, originals: &[String]) -> (String, usize) {
+    let mut restored = vec![false; originals.len()];
+    let placeholder = Regex::new(LENIENT_PLACEHOLDER_PATTERN).unwrap();
+    let text = placeholder.replace_all(text, |captures: &regex::Captures| {
+        let original = captures[1]
+            .parse::<usize>()
+            .ok()
+            .and_then(|number| number.checked_sub(1))
+            .filter(|index| *index < originals.len());
+        match original {
+            Some(index) => {
+                restored[index] = true;
+                originals[index].clone()
+            }
+            None => captures[0].to_string(),
+        }
+    });
+    let missing = restored.iter().filter(|restored| !**restored).count();
+    (text.into_owned(), missing)
+}
+
+// Caps how many provider requests are in flight at once, whichever action or
+// command sent them. Requests over the cap wait for a slot. The semaphore is
+// replaced when `max_concurrent_requests` changes; requests holding a permit
+// from the old one still finish.
+#[derive(Default)]
+struct RequestLimit(Mutex<Option<(usize, Arc<Semaphore>)>>);
+
+impl RequestLimit {
+    async fn acquire(&self, max_concurrent_requests: usize) -> OwnedSemaphorePermit {
+        let cap = max_concurrent_requests.max(1);
+        let semaphore = {
+            let mut current = self.0.lock().unwrap();
+            match &*current {
+                Some((current_cap, semaphore)) if *current_cap == cap => semaphore.clone(),
+                _ => {
+                    let semaphore = Arc::new(Semaphore::new(cap));
+                    *current = Some((cap, semaphore.clone()));
+                    semaphore
+                }
+            }
+        };
+        semaphore
+            .acquire_owned()
+            .await
+            .expect("request semaphore is never closed")
+    }
+}
+
+// The text as it is sent, shared by requests and request previews
+struct PreparedText<'a> {
+    text: String,
+    fence_info: Option<&'a str>, // The answer is put back in a fence like this
+    redactions: Vec<String>,     // The values masked in `text`
+}
+
+// Fenced code is sent without its fence. Sensitive values never leave the
+// machine; the cache and request previews only see them masked too.
+fn prepare_text<'a>(
+    text: &'a str,
+    spec: &PromptSpec<'_>,
+    settings: &Settings,
+) -> Result<PreparedText<'a>, String> {
+    let fence = spec.code.then(|| split_code_fence(text)).flatten();
+    let code = fence.map_or(text, |(_, code)| code);
+    let (masked, redactions) = redact(code, &redaction_patterns(settings)?);
+    Ok(PreparedText {
+        text: masked,
+        fence_info: fence.map(|(info, _)| info),
+        redactions,
+    })
+}
+
+// With `on_chunk`, OpenAI-compatible providers stream the response and pass
+// each piece to it as it arrives. Other providers deliver the whole text at once.
+async fn process_text_with_llm(
+    app_handle: &tauri::AppHandle,
+    text: &str,
+    spec: &PromptSpec<'_>,
+    settings: &Settings,
+    on_chunk: Option<ChunkCallback<'_>>,
+) -> Result<LlmOutput, String> {
+    process_text_with_providers(
+        provider_registry(),
+        Some(&app_handle.state::<RequestLimit>()),
+        text,
+        spec,
+        settings,
+        on_chunk,
+    )
+    .await
+}
+
+// process_text_with_llm without the app, so other providers can stand in.
+// `request_limit` is None when the caller already holds a permit.
+async fn process_text_with_providers(
+    registry: &ProviderRegistry,
+    request_limit: Option<&RequestLimit>,
+    text: &str,
+    spec: &PromptSpec<'_>,
+    settings: &Settings,
+    on_chunk: Option<ChunkCallback<'_>>,
+) -> Result<LlmOutput, String> {
+    let prepared = prepare_text(text, spec, settings)?;
+    let text = prepared.text.as_str();
+    let cache_key = (settings.cache_size > 0).then(|| result_cache_key(text, spec, settings));
+    let mut output = match cache_key
+        .as_deref()
+        .and_then(|key| get_cached_result(key, settings))
+    {
+        Some(output) => {
+            if let Some(on_chunk) = on_chunk {
+                on_chunk(&output.text);
+            }
+            output
+        }
+        None => {
+            // Held through any fallbacks, so a failover doesn't jump the queue
+            let _permit = match request_limit {
+                Some(request_limit) => Some(
+                    request_limit
+                        .acquire(settings.max_concurrent_requests)
+                        .await,
+                ),
+                None => None,
+            };
+            let client = http_client(settings);
+            let mut result =
+                complete_with_provider(registry, text, spec, settings, &client, on_chunk).await;
+            for fallback in settings.fallback_settings() {
+                match &result {
+                    Err(e) if is_failover_error(e) => {
+                        eprintln!("{}, falling back to {}", e, fallback.provider);
+                        let client = http_client(&fallback);
+                        result = complete_with_provider(
+                            registry, text, spec, &fallback, &client, on_chunk,
+                        )
+                        .await
+                        .map(|output| LlmOutput {
+                            fallback_provider: Some(fallback.provider.clone()),
+                            ..output
+                        });
+                    }
+                    _ => break,
+                }
+            }
+            let output = result?;
+            if let Some(key) = cache_key.filter(|_| !output.truncated) {
+                store_cached_result(key, &output, settings);
+            }
+            output
+        }
+    };
+
+    if spec.json_output {
+        // Fall back to the raw text if the model didn't return valid JSON
+        match serde_json::from_str::<StructuredTranslation>(&output.text) {
+            Ok(parsed) => {
+                output.text = parsed.translation.trim().to_string();
+                output.translation_details = Some(parsed.details);
+            }
+            Err(e) => eprintln!("Failed to parse structured translation: {}", e),
+        }
+    }
+
+    output.text = apply_post_processing(
+        output.text,
+        settings,
+        &PostStage::Answer {
+            redactions: &prepared.redactions,
+            code: spec.code,
+        },
+    );
+
+    if spec.code {
+        // Models often fence their answer even when asked not to
+        if let Some((_, code)) = split_code_fence(&output.text) {
+            output.text = code.to_string();
+        }
+        output.text = output
+            .text
+            .trim_end()
+            .trim_start_matches(['\r', '\n'])
+            .to_string();
+        if let Some(info) = prepared.fence_info {
+            output.text = format!("```{}\n{}\n```", info, output.text);
+        }
+    }
+    // A safety net for models that ignore max_tokens
+    if settings.max_result_chars > 0 {
+        if let Some((cut, _)) = output.text.char_indices().nth(settings.max_result_chars) {
+            eprintln!(
+                "Warning: result is longer than max_result_chars ({}), truncating",
+                settings.max_result_chars
+            );
+            output.text.truncate(cut);
+        }
+    }
+    Ok(output)
+}
+
+// The glossary as prompt instructions, sorted so the prompt (and the cache key)
+// is stable. Stops adding entries at MAX_GLOSSARY_CHARS.
+fn glossary_instructions(settings: &Settings) -> Option<String> {
+    let mut entries: Vec<(&String, &String)> = settings
+        .glossary
+        .iter()
+        .filter(|(source, target)| !source.trim().is_empty() && !target.trim().is_empty())
+        .collect();
+    if entries.is_empty() {
+        return None;
+    }
+    entries.sort();
+
+    let mut instructions = "Glossary (always translate these terms exactly as shown):".to_string();
+    let mut included = 0;
+    for (source, target) in &entries {
+        let line = format!("\n- {} -> {}", source.trim(), target.trim());
+        if instructions.len() + line.len() > MAX_GLOSSARY_CHARS {
+            break;
+        }
+        instructions.push_str(&line);
+        included += 1;
+    }
+    if included < entries.len() {
+        eprintln!(
+            "Glossary too long for the prompt, left out {} of {} entries",
+            entries.len() - included,
+            entries.len()
+        );
+    }
+    if included == 0 {
+        return None;
+    }
+    Some(instructions)
+}
+
+// Compiled glossary terms, rebuilt when the glossary changes
+type GlossaryPatterns = Arc<Vec<(Regex, String)>>;
+static GLOSSARY_PATTERNS: Mutex<Option<(HashMap<String, String>, GlossaryPatterns)>> =
+    Mutex::new(None);
+
+// One pattern per term, matching whole words only so "AI" leaves "PAID"
+// alone. Longer terms go first so a term isn't clobbered by a shorter one it
+// contains.
+fn glossary_patterns(settings: &Settings) -> GlossaryPatterns {
+    let mut cache = GLOSSARY_PATTERNS.lock().unwrap();
+    if let Some((glossary, patterns)) = cache.as_ref() {
+        if *glossary == settings.glossary {
+            return patterns.clone();
+        }
+    }
+
+    let mut entries: Vec<(&str, &str)> = settings
+        .glossary
+        .iter()
+        .map(|(source, target)| (source.trim(), target.trim()))
+        .filter(|(source, target)| !source.is_empty() && !target.is_empty() && source != target)
+        .collect();
+    entries.sort_by(|a, b| b.0.len().cmp(&a.0.len()).then(a.0.cmp(b.0)));
+    // A term like "C++" has no word boundary after its last character
+    let boundary = |c: Option<char>| {
+        if c.is_some_and(|c| c.is_alphanumeric() || c == '_') {
+            r"\b"
+        } else {
+            ""
+        }
+    };
+    let patterns: Vec<(Regex, String)> = entries
+        .into_iter()
+        .filter_map(|(source, target)| {
+            let pattern = format!(
+                "{}{}{}",
+                boundary(source.chars().next()),
+                regex::escape(source),
+                boundary(source.chars().last())
+            );
+            Regex::new(&pattern)
+                .map_err(|e| eprintln!("Invalid glossary term {}: {}", source, e))
+                .ok()
+                .map(|regex| (regex, target.to_string()))
+        })
+        .collect();
+    let patterns = Arc::new(patterns);
+    *cache = Some((settings.glossary.clone(), patterns.clone()));
+    patterns
+}
+
+// Replaces glossary source terms the model left untranslated
+fn enforce_glossary(text: &str, settings: &Settings) -> String {
+    let mut result = text.to_string();
+    for (pattern, target) in glossary_patterns(settings).iter() {
+        result = pattern
+            .replace_all(&result, regex::NoExpand(target))
+            .into_owned();
+    }
+    result
+}
+
+// Post-processing for a translation: glossary enforcement, then the optional polish
+async fn finish_translation(
+    app_handle: &tauri::AppHandle,
+    mut output: LlmOutput,
+    settings: &Settings,
+) -> Result<LlmOutput, String> {
+    if settings.enforce_glossary {
+        output.text = enforce_glossary(&output.text, settings);
+    }
+    if settings.polish_after_translate {
+        return polish_translation(app_handle, output).await;
+    }
+    Ok(output)
+}
+
+// The second stage of translating with `polish_after_translate`. It runs with
+// the polish action's settings and keeps the translation's details.
+async fn polish_translation(
+    app_handle: &tauri::AppHandle,
+    translation: LlmOutput,
+) -> Result<LlmOutput, String> {
+    let mut settings = load_settings();
+    settings.apply_action_model(TextAction::Polish);
+    record_request(&settings)?;
+
+    let spec = TextAction::Polish.prompt_spec(&settings, &translation.text);
+    let polished = process_text_with_llm(app_handle, &translation.text, &spec, &settings, None)
+        .await
+        .map_err(|e| format!("Translated, but polishing the translation failed: {}", e))?;
+    Ok(LlmOutput {
+        truncated: translation.truncated || polished.truncated,
+        translation_details: translation.translation_details,
+        ..polished
+    })
+}
+
+// Polishes each text independently, at most `batch_concurrency` at a time.
+// Results are returned in the same order as the input.
+#[tauri::command]
+async fn polish_batch(
+    app_handle: tauri::AppHandle,
+    texts: Vec<String>,
+) -> Vec<Result<String, String>> {
+    let mut settings = load_settings();
+    settings.apply_action_model(TextAction::Polish);
+    if settings.get_current_api_key().is_empty() {
+        let error = format!("API key not configured for provider: {}", settings.provider);
+        return texts.iter().map(|_| Err(error.clone())).collect();
+    }
+
+    let settings = &settings;
+    let app_handle = &app_handle;
+    let mut results: Vec<(usize, Result<String, String>)> =
+        stream::iter(texts.into_iter().enumerate())
+            .map(|(index, text)| async move {
+                if text.trim().is_empty() {
+                    return (index, Err("Text is empty".to_string()));
+                }
+                if let Err(e) = record_request(settings) {
+                    return (index, Err(e));
+                }
+                let text = if settings.normalize_input {
+                    normalize_text(&text, settings)
+                } else {
+                    text
+                };
+                let result = process_text_with_llm(
+                    app_handle,
+                    &text,
+                    &TextAction::Polish.prompt_spec(settings, &text),
+                    settings,
+                    None,
+                )
+                .await
+                .map(|output| output.text);
+                (index, result)
+            })
+            .buffer_unordered(settings.batch_concurrency.max(1))
+            .collect()
+            .await;
+
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[derive(Deserialize)]
+struct ProviderConfig {
+    provider: String,
+    model: String,
+}
+
+#[derive(Serialize)]
+struct ProviderResult {
+    provider: String,
+    model: String,
+    result: Option<String>,
+    error: Option<String>,
+    latency_ms: u64,
+    usage: Option<TokenUsage>,
+}
+
+// Each provider in a comparison gets this long before it is reported as timed out
+const COMPARE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// Runs the same polish against every provider/model pair, up to
+// `max_concurrent_requests` at a time so waiting for a slot doesn't count
+// toward an entry's latency or timeout. A failure only affects its own entry;
+// the cache and fallback providers are bypassed so every entry is a fresh
+// answer from the model it names.
+#[tauri::command]
+async fn compare_providers(
+    app_handle: tauri::AppHandle,
+    text: String,
+    providers: Vec<ProviderConfig>,
+) -> Vec<ProviderResult> {
+    let base = load_settings();
+    let text = if base.normalize_input {
+        normalize_text(&text, &base)
+    } else {
+        text
+    };
+    let text = &text;
+    let app_handle = &app_handle;
+    let runs = providers.into_iter().map(|mut config| {
+        if config.model.is_empty() {
+            config.model = provider_registry()
+                .default_model(&config.provider)
+                .to_string();
+        }
+        let mut settings = base.clone();
+        settings.provider = config.provider.clone();
+        settings.model = config.model.clone();
+        settings.cache_size = 0;
+        settings.fallback_providers.clear();
+        async move {
+            let mut started = std::time::Instant::now();
+            let result = if text.trim().is_empty() {
+                Err("Text is empty".to_string())
+            } else if settings.get_current_api_key().is_empty() {
+                Err(format!(
+                    "API key not configured for provider: {}",
+                    settings.provider
+                ))
+            } else if let Err(e) = record_request(&settings) {
+                Err(e)
+            } else {
+                let spec = TextAction::Polish.prompt_spec(&settings, text);
+                // Other requests may hold the slots too, so the clock starts
+                // once this entry has one
+                let _permit = app_handle
+                    .state::<RequestLimit>()
+                    .acquire(settings.max_concurrent_requests)
+                    .await;
+                started = std::time::Instant::now();
+                tokio::time::timeout(
+                    COMPARE_TIMEOUT,
+                    process_text_with_providers(
+                        provider_registry(),
+                        None,
+                        text,
+                        &spec,
+                        &settings,
+                        None,
+                    ),
+                )
+                .await
+                .unwrap_or_else(|_| {
+                    Err(format!(
+                        "Timed out after {} seconds",
+                        COMPARE_TIMEOUT.as_secs()
+                    ))
+                })
+            };
+            let latency_ms = started.elapsed().as_millis() as u64;
+            let (result, error, usage) = match result {
+                Ok(output) => (Some(output.text), None, output.usage),
+                Err(e) => (None, Some(e), None),
+            };
+            ProviderResult {
+                provider: config.provider,
+                model: config.model,
+                result,
+                error,
+                latency_ms,
+                usage,
+            }
+        }
+    });
+    stream::iter(runs)
+        .buffered(base.max_concurrent_requests.max(1))
+        .collect()
+        .await
+}
+
+// Sends any prompt and text through the usual provider dispatch, with its
+// timeouts, retries and fallbacks, and returns the result without copying it
+// anywhere. `provider` and `model` default to the configured ones.
+#[tauri::command]
+async fn run_prompt(
+    app_handle: tauri::AppHandle,
+    prompt: String,
+    text: String,
+    provider: Option<String>,
+    model: Option<String>,
+) -> Result<String, String> {
+    let mut settings = load_settings();
+    if let Some(provider) = provider.filter(|provider| *provider != settings.provider) {
+        let default_model = provider_registry().get(&provider)?.default_model;
+        settings.model = settings
+            .provider_models
+            .get(&provider)
+            .cloned()
+            .unwrap_or_else(|| default_model.to_string());
+        settings.provider = provider;
+    }
+    if let Some(model) = model.filter(|model| !model.is_empty()) {
+        settings.model = model;
+    }
+    if text.trim().is_empty() {
+        return Err("Text is empty".to_string());
+    }
+    if settings.get_current_api_key().is_empty() {
+        return Err(format!(
+            "API key not configured for provider: {}",
+            settings.provider
+        ));
+    }
+    record_request(&settings)?;
+
+    let spec = PromptSpec {
+        prompt,
+        temperature: 0.3,
+        examples: &[],
+        json_output: false,
+        max_tokens: None,
+        code: false,
+    };
+    let output = process_text_with_llm(&app_handle, &text, &spec, &settings, None).await?;
+    Ok(output.text)
+}
+
+// Describes a failed response, including the provider's error body (which
+// may be JSON or plain text) so misconfigurations are easy to spot
+async fn api_error(response: reqwest::Response) -> String {
+    let status = response.status();
+    let error_text = response.text().await.unwrap_or_default();
+    let error_text = error_text.trim();
+    if error_text.is_empty() {
+        return format!("API request failed with status: {}", status);
+    }
+
+    let error_text = if error_text.chars().count() > MAX_ERROR_BODY_CHARS {
+        format!(
+            "{}...",
+            error_text
+                .chars()
+                .take(MAX_ERROR_BODY_CHARS)
+                .collect::<String>()
+        )
+    } else {
+        error_text.to_string()
+    };
+    format!(
+        "API request failed with status: {} - {}",
+        status, error_text
+    )
+}
+
+// OpenAI's o-series (o1, o3, ...) models
+fn is_reasoning_model(model: &str) -> bool {
+    let mut chars = model.chars();
+    chars.next() == Some('o') && chars.next().is_some_and(|c| c.is_ascii_digit())
+}
+
+// The request that `action` would send for `text` with the current settings,
+// as pretty JSON with the API key redacted so it can go into bug reports
+#[tauri::command]
+fn preview_request(action: TextAction, text: String) -> Result<String, String> {
+    let mut settings = load_settings();
+    settings.apply_action_model(action);
+    let text = if settings.normalize_input {
+        normalize_text(&text, &settings)
+    } else {
+        text
+    };
+    let spec = action.prompt_spec(&settings, &text);
+    let prepared = prepare_text(&text, &spec, &settings)?;
+
+    let (url, authorization, body) = provider_registry().get(&settings.provider)?.api.prepare(
+        &prepared.text,
+        &spec,
+        &settings,
+    )?;
+
+    let mut headers = serde_json::Map::new();
+    if let Some(authorization) = authorization {
+        headers.insert("Authorization".to_string(), authorization.into());
+    }
+    headers.insert("Content-Type".to_string(), "application/json".into());
+    for (name, value) in &extra_headers(&settings) {
+        headers.insert(
+            name.to_string(),
+            value.to_str().unwrap_or_default().to_string().into(),
+        );
+    }
+
+    let preview = serde_json::json!({
+        "method": "POST",
+        "url": url,
+        "headers": headers,
+        "body": body,
+    });
+    serde_json::to_string_pretty(&preview)
+        .map(|json| redact_secrets(&json, &settings))
+        .map_err(|e| format!("Failed to serialize request: {}", e))
+}
+
+// CJK text has no spaces and tokenizes to roughly a token per character
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}' // Hiragana and Katakana
+        | '\u{3400}'..='\u{4dbf}' // CJK Extension A
+        | '\u{4e00}'..='\u{9fff}' // CJK Unified Ideographs
+        | '\u{ac00}'..='\u{d7af}' // Hangul syllables
+        | '\u{f900}'..='\u{faff}' // CJK Compatibility Ideographs
+    )
+}
+
+// Exact token count with OpenAI's tokenizer, None for models it doesn't know.
+// Loading a tokenizer takes a while, so each model's is kept.
+#[cfg(feature = "tiktoken")]
+fn tiktoken_count(text: &str, model: &str) -> Option<usize> {
+    type Tokenizers = Mutex<HashMap<String, Option<Arc<tiktoken_rs::CoreBPE>>>>;
+    static TOKENIZERS: OnceLock<Tokenizers> = OnceLock::new();
+    let bpe = TOKENIZERS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(model.to_string())
+        .or_insert_with(|| tiktoken_rs::get_bpe_from_model(model).ok().map(Arc::new))
+        .clone()?;
+    Some(bpe.encode_with_special_tokens(text).len())
+}
+
+#[cfg(not(feature = "tiktoken"))]
+fn tiktoken_count(_text: &str, _model: &str) -> Option<usize> {
+    None
+}
+
+// Token count for `text`, exact for OpenAI models when built with the
+// `tiktoken` feature. Otherwise it's a rough count without a tokenizer: about
+// four characters per token for other scripts, as the providers document for
+// English. GPT-4o and the o-series use a larger vocabulary that needs about 30%
+// fewer tokens for CJK.
+#[tauri::command]
+fn estimate_tokens(text: String, model: String) -> usize {
+    if let Some(count) = tiktoken_count(&text, &model) {
+        return count;
+    }
+    let cjk_tokens_per_char = if model.starts_with("gpt-4o") || is_reasoning_model(&model) {
+        0.7
+    } else {
+        1.0
+    };
+    let (cjk, other) = text.chars().fold((0usize, 0usize), |(cjk, other), c| {
+        if is_cjk(c) {
+            (cjk + 1, other)
+        } else {
+            (cjk, other + 1)
+        }
+    });
+    (cjk as f64 * cjk_tokens_per_char + other as f64 / 4.0).ceil() as usize
+}
+
+// Fills the {text}, {language} and {tone} placeholders in a prompt. {text} is
+// replaced last so placeholders inside the user's text are left alone.
+fn render_prompt(template: &str, text: &str, settings: &Settings) -> String {
+    let mut prompt = template.replace("{tone}", &settings.tone);
+    if prompt.contains("{language}") {
+        let language = detect_language(text).unwrap_or("the same language as the input");
+        prompt = prompt.replace("{language}", language);
+    }
+    prompt.replace("{text}", text)
+}
+
+// The instructions and user message for `text`. With a {text} placeholder the
+// rendered prompt is the whole message, otherwise the text follows the prompt.
+fn prompt_messages(
+    spec: &PromptSpec<'_>,
+    text: &str,
+    settings: &Settings,
+) -> (Option<String>, String) {
+    if spec.prompt.contains("{text}") {
+        (None, render_prompt(&spec.prompt, text, settings))
+    } else {
+        (
+            Some(render_prompt(&spec.prompt, text, settings)),
+            text.to_string(),
+        )
+    }
+}
+
+// Headers never taken from `extra_headers`, so a gateway header can't
+// replace the API key or break the JSON body
+const PROTECTED_HEADERS: [&str; 2] = ["authorization", "content-type"];
+
+// The current provider's `extra_headers`, e.g. OpenRouter's HTTP-Referer and X-Title
+fn extra_headers(settings: &Settings) -> reqwest::header::HeaderMap {
+    let mut headers = reqwest::header::HeaderMap::new();
+    let Some(extra_headers) = settings.extra_headers.get(&settings.provider) else {
+        return headers;
+    };
+    for (name, value) in extra_headers {
+        if PROTECTED_HEADERS.contains(&name.to_lowercase().as_str()) {
+            eprintln!("Ignoring extra header {}, it is set by the app", name);
+            continue;
+        }
+        match (
+            reqwest::header::HeaderName::from_bytes(name.as_bytes()),
+            reqwest::header::HeaderValue::from_str(value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.insert(name, value);
+            }
+            _ => eprintln!("Ignoring invalid extra header {}", name),
+        }
+    }
+    headers
+}
+
+// Applies `patch` to `target` like a JSON merge patch (RFC 7396): objects are
+// merged key by key, null removes a key and anything else replaces the value
+fn merge_json(target: &mut serde_json::Value, patch: &serde_json::Value) {
+    let serde_json::Value::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = serde_json::Value::Object(serde_json::Map::new());
+    }
+    if let serde_json::Value::Object(target) = target {
+        for (key, value) in patch {
+            if value.is_null() {
+                target.remove(key);
+            } else {
+                merge_json(
+                    target.entry(key.clone()).or_insert(serde_json::Value::Null),
+                    value,
+                );
+            }
+        }
+    }
+}
+
+// The JSON body for `request`, with the current provider's `extra_body`
+// merged over it for parameters the app doesn't set itself
+fn request_body(
+    request: &impl Serialize,
+    settings: &Settings,
+) -> Result<serde_json::Value, String> {
+    let mut body =
+        serde_json::to_value(request).map_err(|e| format!("Failed to serialize request: {}", e))?;
+    match settings.extra_body.get(&settings.provider) {
+        Some(extra_body) if extra_body.is_object() => merge_json(&mut body, extra_body),
+        Some(_) => eprintln!(
+            "Ignoring extra_body for {}, it must be a JSON object",
+            settings.provider
+        ),
+        None => {}
+    }
+    Ok(body)
+}
+
+fn openai_url(settings: &Settings) -> String {
+    format!("{}/chat/completions", settings.get_current_base_url())
+}
+
+fn build_openai_request(
+    text: &str,
+    spec: &PromptSpec<'_>,
+    settings: &Settings,
+    stream: bool,
+) -> Result<serde_json::Value, String> {
+    let (instructions, message) = prompt_messages(spec, text, settings);
+    let mut messages: Vec<_> = instructions
+        .into_iter()
+        .map(|content| OpenAIMessage {
+            role: "system".to_string(),
+            content,
+        })
+        .collect();
+    for (example_input, example_output) in spec.examples {
+        messages.push(OpenAIMessage {
+            role: "user".to_string(),
+            content: example_input.clone(),
+        });
+        messages.push(OpenAIMessage {
+            role: "assistant".to_string(),
+            content: example_output.clone(),
+        });
+    }
+    messages.push(OpenAIMessage {
+        role: "user".to_string(),
+        content: message,
+    });
+
+    // Reasoning models reject sampling parameters and the old token limit name
+    let reasoning = is_reasoning_model(&settings.model);
+    let max_tokens = spec.max_tokens(settings);
+    let request = OpenAIRequest {
+        model: settings.model.clone(),
+        messages,
+        max_tokens: (!reasoning).then_some(max_tokens),
+        max_completion_tokens: reasoning.then_some(max_tokens),
+        temperature: (!reasoning).then_some(spec.temperature),
+        top_p: settings.top_p.filter(|_| !reasoning),
+        response_format: spec.json_output.then(|| OpenAIResponseFormat {
+            format_type: "json_object".to_string(),
+        }),
+        stream: stream.then_some(true),
+    };
+    request_body(&request, settings)
+}
+
+async fn complete_with_openai(
+    text: &str,
+    spec: &PromptSpec<'_>,
+    settings: &Settings,
+    client: &reqwest::Client,
+    on_chunk: Option<ChunkCallback<'_>>,
+) -> Result<LlmOutput, String> {
+    // JSON output is only useful once complete, so it is never streamed
+    let on_chunk = on_chunk.filter(|_| !spec.json_output);
+    let request = build_openai_request(text, spec, settings, on_chunk.is_some())?;
+    let url = openai_url(settings);
+    log_request(settings, &url, &request);
+
+    let response = client
+        .post(&url)
+        .header(
+            "Authorization",
+            format!("Bearer {}", settings.get_current_api_key()),
+        )
+        .header("Content-Type", "application/json")
+        .headers(extra_headers(settings))
+        .json(&request)
+        .send()
+        .await
+        .map_err(|e| format!("Request failed: {}", e))?;
+
+    if !response.status().is_success() {
+        let error = api_error(response).await;
+        debug_log(settings, "Error response", &error);
+        return Err(error);
+    }
+
+    if let Some(on_chunk) = on_chunk {
+        return read_openai_stream(response, settings, on_chunk).await;
+    }
+
+    let openai_response: OpenAIResponse = read_response(response, settings).await?;
+
+    let choice = openai_response.choices.first();
+    let output = parse_llm_text(
+        choice.map(|choice| choice.message.content.as_str()),
+        choice.and_then(|choice| choice.finish_reason.as_deref()) == Some("length"),
+    )?;
+    Ok(LlmOutput {
+        usage: openai_response.usage.map(|usage| TokenUsage {
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+        }),
+        ..output
+    })
+}
+
+// Collects a server-sent event stream of `data: {...}` lines, ending at
+// `data: [DONE]`
+async fn read_openai_stream(
+    response: reqwest::Response,
+    settings: &Settings,
+    on_chunk: ChunkCallback<'_>,
+) -> Result<LlmOutput, String> {
+    read_openai_events(response.bytes_stream(), settings, on_chunk).await
+}
+
+// Lines are split on the raw bytes and only decoded once complete, since a
+// character can be split between two network chunks
+async fn read_openai_events<B: AsRef<[u8]>, E: std::fmt::Display>(
+    mut body: impl futures::Stream<Item = Result<B, E>> + Unpin,
+    settings: &Settings,
+    on_chunk: ChunkCallback<'_>,
+) -> Result<LlmOutput, String> {
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut content = String::new();
+    let mut truncated = false;
+
+    'read: while let Some(bytes) = body.next().await {
+        let bytes = bytes.map_err(|e| format!("Failed to read stream: {}", e))?;
+        buffer.extend_from_slice(bytes.as_ref());
+
+        while let Some(newline) = buffer.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue;
+            };
+            let data = data.trim();
+            if data == "[DONE]" {
+                break 'read;
+            }
+
+            let chunk: OpenAIStreamChunk = serde_json::from_str(data)
+                .map_err(|e| format!("Failed to parse stream chunk: {}", e))?;
+            if let Some(choice) = chunk.choices.first() {
+                if let Some(delta) = choice.delta.content.as_deref() {
+                    content.push_str(delta);
+                    on_chunk(delta);
+                }
+                truncated |= choice.finish_reason.as_deref() == Some("length");
+            }
+        }
+    }
+
+    debug_log(settings, "Streamed response", &content);
+    parse_llm_text(Some(&content), truncated)
+}
+
+// The key is part of the URL for Gemini
+fn gemini_url(settings: &Settings, api_key: &str) -> String {
+    let base_url = settings.get_current_base_url();
+    if base_url.contains("generateContent") {
+        format!("{}?key={}", base_url, api_key)
+    } else {
+        format!(
+            "{}/v1beta/models/{}:generateContent?key={}",
+            base_url, settings.model, api_key
+        )
+    }
+}
+
+fn build_gemini_request(
+    text: &str,
+    spec: &PromptSpec<'_>,
+    settings: &Settings,
+) -> Result<serde_json::Value, String> {
+    let (instructions, message) = prompt_messages(spec, text, settings);
+    let mut parts: Vec<String> = instructions.into_iter().collect();
+    for (example_input, example_output) in spec.examples {
+        parts.push(format!(
+            "Example input:\n{}\n\nExample output:\n{}",
+            example_input, example_output
+        ));
+    }
+    parts.push(message);
+    let combined_prompt = parts.join("\n\n");
+
+    let request = GeminiRequest {
+        contents: vec![GeminiContent {
+            parts: vec![GeminiPart {
+                text: combined_prompt,
+            }],
+        }],
+        generation_config: GeminiGenerationConfig {
+            temperature: spec.temperature,
+            max_output_tokens: spec.max_tokens(settings),
+            top_p: settings.top_p,
+        },
+    };
+    request_body(&request, settings)
+}
+
+async fn complete_with_gemini(
+    text: &str,
+    spec: &PromptSpec<'_>,
+    settings: &Settings,
+    client: &reqwest::Client,
+) -> Result<LlmOutput, String> {
+    let request = build_gemini_request(text, spec, settings)?;
+    let url = gemini_url(settings, &settings.get_current_api_key());
+    log_request(settings, &url, &request);
+
+    let response = client
+        .post(&url)
+        .header("Content-Type", "application/json")
+        .headers(extra_headers(settings))
+        .json(&request)
+        .send()
+        .await
+        .map_err(|e| format!("Request failed: {}", e))?;
+
+    if !response.status().is_success() {
+        let error = api_error(response).await;
+        debug_log(settings, "Error response", &error);
+        return Err(error);
+    }
+
+    let gemini_response: GeminiResponse = read_response(response, settings).await?;
+
+    let candidate = gemini_response.candidates.first();
+    let output = parse_llm_text(
+        candidate
+            .and_then(|candidate| candidate.content.parts.first())
+            .map(|part| part.text.as_str()),
+        candidate.and_then(|candidate| candidate.finish_reason.as_deref()) == Some("MAX_TOKENS"),
+    )?;
+    Ok(LlmOutput {
+        usage: gemini_response.usage_metadata.map(|usage| TokenUsage {
+            input_tokens: usage.prompt_token_count,
+            output_tokens: usage.candidates_token_count,
+        }),
+        ..output
+    })
+}
+
+fn cohere_url(settings: &Settings) -> String {
+    format!("{}/v1/chat", settings.get_current_base_url())
+}
+
+fn build_cohere_request(
+    text: &str,
+    spec: &PromptSpec<'_>,
+    settings: &Settings,
+) -> Result<serde_json::Value, String> {
+    let chat_history = spec
+        .examples
+        .iter()
+        .flat_map(|(example_input, example_output)| {
+            [
+                CohereMessage {
+                    role: "USER".to_string(),
+                    message: example_input.clone(),
+                },
+                CohereMessage {
+                    role: "CHATBOT".to_string(),
+                    message: example_output.clone(),
+                },
+            ]
+        })
+        .collect();
+
+    let (instructions, message) = prompt_messages(spec, text, settings);
+    let request = CohereRequest {
+        model: settings.model.clone(),
+        message,
+        chat_history,
+        preamble: instructions,
+        max_tokens: spec.max_tokens(settings),
+        temperature: spec.temperature,
+        p: settings.top_p,
+    };
+    request_body(&request, settings)
+}
+
+async fn complete_with_cohere(
+    text: &str,
+    spec: &PromptSpec<'_>,
+    settings: &Settings,
+    client: &reqwest::Client,
+) -> Result<LlmOutput, String> {
+    let request = build_cohere_request(text, spec, settings)?;
+    let url = cohere_url(settings);
+    log_request(settings, &url, &request);
+
+    let response = client
+        .post(&url)
+        .header(
+            "Authorization",
+            format!("Bearer {}", settings.get_current_api_key()),
+        )
+        .header("Content-Type", "application/json")
+        .headers(extra_headers(settings))
+        .json(&request)
+        .send()
+        .await
+        .map_err(|e| format!("Request failed: {}", e))?;
+
+    if !response.status().is_success() {
+        let error = api_error(response).await;
+        debug_log(settings, "Error response", &error);
+        return Err(error);
+    }
+
+    let cohere_response: CohereResponse = read_response(response, settings).await?;
+
+    let output = parse_llm_text(
+        Some(cohere_response.text.as_str()),
+        cohere_response.finish_reason.as_deref() == Some("MAX_TOKENS"),
+    )?;
+    Ok(LlmOutput {
+        usage: cohere_response
+            .meta
+            .and_then(|meta| meta.billed_units)
+            .map(|units| TokenUsage {
+                input_tokens: units.input_tokens,
+                output_tokens: units.output_tokens,
+            }),
+        ..output
+    })
+}
+
+// Writes `text` to the clipboard. With `verify_clipboard_write`, reads it
+// back and rewrites a few times if another app raced us.
+async fn write_clipboard(
+    app_handle: &tauri::AppHandle,
+    text: &str,
+    settings: &Settings,
+) -> Result<(), String> {
+    let mut clipboard = app_handle.clipboard_manager();
+    let mut attempt = 0;
+    loop {
+        clipboard
+            .write_text(text.to_string())
+            .map_err(|e| format!("Failed to write to clipboard: {}", e))?;
+        if !settings.verify_clipboard_write {
+            return Ok(());
+        }
+
+        tokio::time::sleep(CLIPBOARD_VERIFY_DELAY).await;
+        if clipboard.read_text().ok().flatten().as_deref() == Some(text) {
+            return Ok(());
+        }
+        if attempt == CLIPBOARD_VERIFY_RETRIES {
+            return Err("Clipboard did not update after writing".to_string());
+        }
+        attempt += 1;
+        eprintln!("Clipboard verification failed, retrying");
+    }
+}
+
+// The most recent result, kept in memory only so it can be re-copied without
+// another API call
+#[derive(Default)]
+struct LastResult(Mutex<Option<String>>);
+
+// The binding and input of the most recent action, for creative retries
+#[derive(Default)]
+struct LastRequest(Mutex<Option<(ShortcutBinding, String)>>);
+
+// Re-runs the last action on the same input with a higher temperature, for
+// when a result is too close to the original
+#[tauri::command]
+async fn regenerate_creative(app_handle: tauri::AppHandle) -> Result<(), String> {
+    let last_request = app_handle.state::<LastRequest>().0.lock().unwrap().clone();
+    let Some((binding, text)) = last_request else {
+        return Err("Nothing to regenerate yet".to_string());
+    };
+    let action = binding.action;
+    if action.is_offline() {
+        return Err(format!("{} doesn't use a model", action.label()));
+    }
+    let settings = binding_settings(&binding);
+    if settings.get_current_api_key().is_empty() {
+        return Err(format!(
+            "API key not configured for provider: {}",
+            settings.provider
+        ));
+    }
+    record_request(&settings)?;
+
+    let mut spec = action.prompt_spec(&settings, &text);
+    if let Some(prompt) = &binding.prompt {
+        spec.prompt = prompt.clone();
+        spec.json_output = false;
+    }
+    spec.temperature = (spec.temperature + CREATIVE_TEMPERATURE_BOOST).min(1.0);
+
+    update_tray_icon_processing(&app_handle, true);
+    if settings.preview_before_apply || settings.safe_mode {
+        preview_text_action(&app_handle, action, &text, &spec, &settings).await;
+        update_tray_icon_processing(&app_handle, false);
+        return Ok(());
+    }
+    let result = request_text_action(&app_handle, action, &text, &spec, &settings).await;
+    deliver_result(&app_handle, &binding, &text, result, &settings).await;
+    Ok(())
+}
+
+#[tauri::command]
+async fn copy_last_result(app_handle: tauri::AppHandle) -> Result<(), String> {
+    let last_result = app_handle.state::<LastResult>().0.lock().unwrap().clone();
+    let Some(text) = last_result else {
+        return Err("No result to recall yet".to_string());
+    };
+
+    let settings = load_settings();
+    write_clipboard(&app_handle, &text, &settings).await?;
+    show_notification(
+        &app_handle,
+        settings.title("last_result_copied"),
+        &format!("Copied to clipboard:\n{}", notification_preview(&text)),
+        settings.notification_urgency,
+        &settings,
+    );
+    Ok(())
+}
+
+// What the preview window shows. It is kept here as well as sent as events
+// because chunks can arrive before the window has finished loading.
+#[derive(Serialize, Clone, Default)]
+struct PreviewState {
+    text: String,
+    done: bool,
+    error: Option<String>,
+    safe_mode: bool, // The window is the only way to get the result
+    #[serde(skip)]
+    review: Option<(TextAction, String)>, // The action and its input, for keep_reviews
+    #[serde(skip)]
+    sound: Option<String>, // The action's completion sound, played on accept
+}
+
+#[derive(Default)]
+struct Preview(Mutex<PreviewState>);
+
+fn open_preview_window(app_handle: &tauri::AppHandle) {
+    *app_handle.state::<Preview>().0.lock().unwrap() = PreviewState::default();
+
+    if let Some(window) = app_handle.get_window("preview") {
+        let _ = window.emit("polish:reset", ());
+        let _ = window.show();
+        let _ = window.set_focus();
+    } else if let Err(e) = tauri::WindowBuilder::new(
+        app_handle,
+        "preview",
+        tauri::WindowUrl::App("preview.html".into()),
+    )
+    .title("Polish Language - Preview")
+    .inner_size(500.0, 400.0)
+    .always_on_top(true)
+    .build()
+    {
+        eprintln!("Failed to open preview window: {}", e);
+    }
+}
+
+// Puts a finished result in the preview window, so it isn't lost when it
+// can't be delivered
+fn show_result_in_preview(app_handle: &tauri::AppHandle, text: &str) {
+    open_preview_window(app_handle);
+    let state = {
+        let preview = app_handle.state::<Preview>();
+        let mut state = preview.0.lock().unwrap();
+        state.text = text.to_string();
+        state.done = true;
+        state.clone()
+    };
+    let _ = app_handle.emit_to("preview", "polish:done", state);
+}
+
+// Shows the result in the preview window instead of applying it. With
+// `stream`, the text builds up there as it arrives.
+async fn preview_text_action(
+    app_handle: &tauri::AppHandle,
+    action: TextAction,
+    text: &str,
+    spec: &PromptSpec<'_>,
+    settings: &Settings,
+) {
+    open_preview_window(app_handle);
+    {
+        let preview = app_handle.state::<Preview>();
+        let mut state = preview.0.lock().unwrap();
+        state.safe_mode = settings.safe_mode;
+        state.review = Some((action, text.to_string()));
+        state.sound = settings.action_sound(action).map(str::to_string);
+    }
+
+    let on_chunk = |chunk: &str| {
+        app_handle
+            .state::<Preview>()
+            .0
+            .lock()
+            .unwrap()
+            .text
+            .push_str(chunk);
+        let _ = app_handle.emit_to("preview", "polish:chunk", chunk);
+    };
+    let on_chunk = settings.stream.then_some(&on_chunk as ChunkCallback);
+
+    let result = process_text_with_llm(app_handle, text, spec, settings, on_chunk).await;
+    let result = match result {
+        Ok(output) if action == TextAction::Translate => {
+            finish_translation(app_handle, output, settings).await
+        }
+        result => result,
+    };
+    let preview = app_handle.state::<Preview>();
+    let state = {
+        let mut state = preview.0.lock().unwrap();
+        match result {
+            Ok(output) => {
+                warn_if_truncated(app_handle, &output, settings);
+                warn_if_low_confidence(app_handle, &output, settings);
+                // The explanation is shown with the result, so it isn't wrapped
+                state.text = if action == TextAction::ImproveAndExplain {
+                    output.text
+                } else {
+                    apply_post_processing(output.text, settings, &PostStage::Result(action))
+                };
+            }
+            Err(e) => {
+                eprintln!("Failed to process text for preview: {}", e);
+                state.error = Some(e);
+            }
+        }
+        state.done = true;
+        state.clone()
+    };
+    let _ = app_handle.emit_to("preview", "polish:done", state);
+}
+
+#[tauri::command]
+fn get_preview_state(preview: tauri::State<Preview>) -> PreviewState {
+    preview.0.lock().unwrap().clone()
+}
+
+// What the confirm window asks about
+#[derive(Serialize, Clone)]
+struct ConfirmState {
+    action: &'static str,
+    chars: usize,
+    estimated_tokens: usize,
+    provider: String,
+}
+
+struct PendingConfirm {
+    state: ConfirmState,
+    answer: tokio::sync::oneshot::Sender<bool>,
+}
+
+// The large input waiting for an answer. A newer one replaces it, which
+// drops its sender and so cancels it.
+#[derive(Default)]
+struct Confirm(Mutex<Option<PendingConfirm>>);
+
+// Asks in the confirm window whether to send a large selection. Closing the
+// window counts as cancelling.
+async fn confirm_large_input(
+    app_handle: &tauri::AppHandle,
+    action: TextAction,
+    text: &str,
+    settings: &Settings,
+) -> bool {
+    let (answer, answered) = tokio::sync::oneshot::channel();
+    let state = ConfirmState {
+        action: action.verb(),
+        chars: text.chars().count(),
+        estimated_tokens: estimate_tokens(text.to_string(), settings.model.clone()),
+        provider: settings.provider.clone(),
+    };
+    *app_handle.state::<Confirm>().0.lock().unwrap() = Some(PendingConfirm {
+        state: state.clone(),
+        answer,
+    });
+
+    if let Some(window) = app_handle.get_window("confirm") {
+        let _ = window.emit("confirm:reset", state);
+        let _ = window.show();
+        let _ = window.set_focus();
+    } else {
+        match tauri::WindowBuilder::new(
+            app_handle,
+            "confirm",
+            tauri::WindowUrl::App("confirm.html".into()),
+        )
+        .title("Polish Language - Confirm")
+        .inner_size(420.0, 260.0)
+        .resizable(false)
+        .always_on_top(true)
+        .build()
+        {
+            Ok(window) => {
+                let app_handle = app_handle.clone();
+                window.on_window_event(move |event| {
+                    if matches!(event, tauri::WindowEvent::Destroyed) {
+                        app_handle.state::<Confirm>().0.lock().unwrap().take();
+                    }
+                });
+            }
+            Err(e) => {
+                eprintln!("Failed to open confirm window: {}", e);
+                return false;
+            }
+        }
+    }
+
+    answered.await.unwrap_or(false)
+}
+
+#[tauri::command]
+fn get_confirm_state(confirm: tauri::State<Confirm>) -> Option<ConfirmState> {
+    confirm
+        .0
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|pending| pending.state.clone())
+}
+
+#[tauri::command]
+fn answer_confirm(app_handle: tauri::AppHandle, accepted: bool) {
+    if let Some(pending) = app_handle.state::<Confirm>().0.lock().unwrap().take() {
+        let _ = pending.answer.send(accepted);
+    }
+    if let Some(window) = app_handle.get_window("confirm") {
+        let _ = window.close();
+    }
+}
+
+// Copies the (possibly edited) preview text and closes the preview window
+#[tauri::command]
+async fn accept_preview(app_handle: tauri::AppHandle, text: String) -> Result<(), String> {
+    let mut settings = load_settings();
+    write_clipboard(&app_handle, &text, &settings).await?;
+    let (review, sound) = {
+        let preview = app_handle.state::<Preview>();
+        let mut state = preview.0.lock().unwrap();
+        (state.review.take(), state.sound.take())
+    };
+    if let Some((action, original)) = review.filter(|_| settings.keep_reviews) {
+        settings.apply_action_model(action);
+        if let Err(e) = append_review(&settings, action, &original, &text) {
+            eprintln!("{}", e);
+        }
+    }
+    *app_handle.state::<LastResult>().0.lock().unwrap() = Some(text);
+
+    if settings.sound_enabled {
+        play_completion_sound(sound.as_deref());
+    }
+    if let Some(window) = app_handle.get_window("preview") {
+        let _ = window.close();
+    }
+    Ok(())
+}
+
+// Some apps update the selection a moment after the shortcut fires, so wait
+// `selection_capture_delay_ms` first and retry once if nothing was captured
+async fn capture_selection(
+    app_handle: &tauri::AppHandle,
+    settings: &Settings,
+) -> Result<String, String> {
+    if settings.selection_capture_delay_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(settings.selection_capture_delay_ms)).await;
+    }
+
+    // Text selected inside tmux never reaches the terminal's own selection
+    let in_terminal = settings.terminal_integration
+        && frontmost_bundle_id()
+            .is_some_and(|bundle_id| TERMINAL_BUNDLE_IDS.contains(&bundle_id.as_str()));
+    if in_terminal {
+        match read_tmux_buffer() {
+            Ok(text) if !text.trim().is_empty() => return Ok(text),
+            Ok(_) => eprintln!("The tmux buffer is empty"),
+            Err(e) => eprintln!("{}", e),
+        }
+        eprintln!("Using the clipboard as the terminal selection");
+        return app_handle
+            .clipboard_manager()
+            .read_text()
+            .map(Option::unwrap_or_default)
+            .map_err(|e| e.to_string());
+    }
+
+    let mut text = get_selected_text().map_err(|e| e.to_string());
+    if matches!(&text, Ok(text) if text.trim().is_empty()) {
+        tokio::time::sleep(SELECTION_RETRY_DELAY).await;
+        text = get_selected_text().map_err(|e| e.to_string());
+    }
+
+    let captured = matches!(&text, Ok(text) if !text.trim().is_empty());
+    if !captured && settings.linux_primary_selection && cfg!(target_os = "linux") {
+        match read_primary_selection() {
+            Ok(text) => return Ok(text),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+    text
+}
+
+// Terminal emulators whose selection may be a tmux copy instead
+const TERMINAL_BUNDLE_IDS: [&str; 7] = [
+    "com.apple.Terminal",
+    "com.googlecode.iterm2",
+    "io.alacritty",
+    "net.kovidgoyal.kitty",
+    "com.github.wez.wezterm",
+    "com.mitchellh.ghostty",
+    "dev.warp.Warp-Stable",
+];
+
+// Apps launched from Finder don't get the shell's PATH, so also look where
+// Homebrew installs tmux
+const TMUX_PROGRAMS: [&str; 3] = ["tmux", "/opt/homebrew/bin/tmux", "/usr/local/bin/tmux"];
+
+#[cfg(target_os = "macos")]
+fn frontmost_bundle_id() -> Option<String> {
+    use cocoa::base::{id, nil};
+    use cocoa::foundation::NSString;
+    use objc::{class, msg_send, sel, sel_impl};
+
+    unsafe {
+        let workspace: id = msg_send![class!(NSWorkspace), sharedWorkspace];
+        let app: id = msg_send![workspace, frontmostApplication];
+        if app == nil {
+            return None;
+        }
+        let bundle_id: id = msg_send![app, bundleIdentifier];
+        if bundle_id == nil {
+            return None;
+        }
+        let bytes = bundle_id.UTF8String();
+        Some(
+            std::ffi::CStr::from_ptr(bytes)
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+}
+
+// Other platforms have no bundle identifiers to match
+#[cfg(not(target_os = "macos"))]
+fn frontmost_bundle_id() -> Option<String> {
+    None
+}
+
+// Reads the most recent tmux paste buffer, which is where copy mode puts the selection
+fn read_tmux_buffer() -> Result<String, String> {
+    let mut error = String::new();
+    for program in TMUX_PROGRAMS {
+        match std::process::Command::new(program)
+            .arg("show-buffer")
+            .output()
+        {
+            Ok(output) if output.status.success() => {
+                eprintln!("Captured the tmux buffer with {}", program);
+                return Ok(String::from_utf8_lossy(&output.stdout).into_owned());
+            }
+            Ok(output) => {
+                return Err(format!(
+                    "tmux show-buffer failed: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ))
+            }
+            Err(e) => error = format!("Failed to run tmux: {}", e),
+        }
+    }
+    Err(error)
+}
+
+// Reads the X11/Wayland PRIMARY selection (the highlighted text) with the
+// clipboard tool for the current session type
+fn read_primary_selection() -> Result<String, String> {
+    let wayland = std::env::var("XDG_SESSION_TYPE").is_ok_and(|session| session == "wayland")
+        || std::env::var_os("WAYLAND_DISPLAY").is_some();
+    let (program, args): (&str, &[&str]) = if wayland {
+        ("wl-paste", &["--primary", "--no-newline"])
+    } else {
+        ("xclip", &["-o", "-selection", "primary"])
+    };
+
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} failed: {}",
+            program,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    eprintln!("Captured the primary selection with {}", program);
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// How long the retry shortcut stays registered after a failure
+const RETRY_OFFER_WINDOW: Duration = Duration::from_secs(15);
+
+#[derive(Default)]
+struct RetryOfferState {
+    generation: u64, // Bumped per offer, so an old timer can't withdraw a newer one
+    shortcut: Option<String>, // Registered while an offer is open
+}
+
+#[derive(Default)]
+struct RetryOffer(Mutex<RetryOfferState>);
+
+// Registers `retry_shortcut` for RETRY_OFFER_WINDOW to run the last request
+// again. Returns whether the offer was made.
+fn offer_retry(app_handle: &tauri::AppHandle, settings: &Settings) -> bool {
+    let state = app_handle.state::<RetryOffer>();
+    let mut offer = state.0.lock().unwrap();
+    if offer.shortcut.is_none() {
+        let app_handle_retry = app_handle.clone();
+        if let Err(e) =
+            app_handle
+                .global_shortcut_manager()
+                .register(&settings.retry_shortcut, move || {
+                    tauri::async_runtime::spawn(retry_last_request(app_handle_retry.clone()));
+                })
+        {
+            eprintln!("Failed to register retry shortcut: {}", e);
+            return false;
+        }
+        offer.shortcut = Some(settings.retry_shortcut.clone());
+    }
+    offer.generation += 1;
+    let generation = offer.generation;
+
+    let app_handle = app_handle.clone();
+    tauri::async_runtime::spawn(async move {
+        tokio::time::sleep(RETRY_OFFER_WINDOW).await;
+        withdraw_retry_offer(&app_handle, Some(generation));
+    });
+    true
+}
+
+// Unregisters the retry shortcut, unless a newer offer than `generation` was made
+fn withdraw_retry_offer(app_handle: &tauri::AppHandle, generation: Option<u64>) {
+    let state = app_handle.state::<RetryOffer>();
+    let mut offer = state.0.lock().unwrap();
+    if generation.is_some_and(|generation| generation != offer.generation) {
+        return;
+    }
+    if let Some(shortcut) = offer.shortcut.take() {
+        if let Err(e) = app_handle.global_shortcut_manager().unregister(&shortcut) {
+            eprintln!("Failed to unregister retry shortcut: {}", e);
+        }
+    }
+}
+
+// Runs the failed request again on the same input
+async fn retry_last_request(app_handle: tauri::AppHandle) {
+    withdraw_retry_offer(&app_handle, None);
+    let last_request = app_handle.state::<LastRequest>().0.lock().unwrap().clone();
+    if let Some((binding, text)) = last_request {
+        let settings = binding_settings(&binding);
+        run_text_action(app_handle, binding, text, settings).await;
+    }
+}
+
+// The settings an action runs with, after its per-action and binding overrides
+fn binding_settings(binding: &ShortcutBinding) -> Settings {
+    let mut settings = load_settings();
+    settings.apply_action_model(binding.action);
+    if let Some(model) = &binding.model {
+        settings.model = model.clone();
+    }
+    settings
+}
+
+// Runs the binding's action on the current selection and delivers the result.
+// Shared by every shortcut.
+async fn handle_text_action(app_handle: tauri::AppHandle, binding: ShortcutBinding) {
+    let settings = binding_settings(&binding);
+    let captured = if binding.from_clipboard {
+        app_handle
+            .clipboard_manager()
+            .read_text()
+            .map(Option::unwrap_or_default)
+            .map_err(|e| e.to_string())
+    } else {
+        capture_selection(&app_handle, &settings).await
+    };
+    let selected_text = match captured {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Error getting selected text: {}", e);
+            return;
+        }
+    };
+
+    // Skip empty and accidental tiny selections
+    let length = selected_text.trim().chars().count();
+    if length == 0 || length < settings.min_selection_length {
+        if length > 0 {
+            eprintln!(
+                "Ignoring {}-character selection, shorter than min_selection_length",
+                length
+            );
+        }
+        return;
+    }
+
+    let binding = if binding.action == TextAction::ConvertCase && binding.case.is_none() {
+        ShortcutBinding {
+            case: Some(next_case(&app_handle, &selected_text)),
+            ..binding
+        }
+    } else {
+        binding
+    };
+
+    // Code selected with the polish shortcut gets the code prompt instead
+    if settings.auto_detect_code
+        && binding.action == TextAction::Polish
+        && binding.prompt.is_none()
+        && looks_like_code(&selected_text)
+    {
+        eprintln!("Selection looks like code, polishing it as code");
+        let binding = ShortcutBinding {
+            action: TextAction::CodePolish,
+            ..binding
+        };
+        let settings = binding_settings(&binding);
+        run_text_action(app_handle, binding, selected_text, settings).await;
+        return;
+    }
+
+    run_text_action(app_handle, binding, selected_text, settings).await;
+}
+
+// Everything after capturing the selection: the request and delivering its result
+async fn run_text_action(
+    app_handle: tauri::AppHandle,
+    binding: ShortcutBinding,
+    selected_text: String,
+    settings: Settings,
+) {
+    let action = binding.action;
+    let offline = action.is_offline();
+    if !offline && settings.get_current_api_key().is_empty() {
+        notify_error(
+            &app_handle,
+            Some(action),
+            settings.action_title(action, "failed", action.failed_title()),
+            &format!("API key not configured for provider: {}", settings.provider),
+            &settings,
+        );
+        return;
+    }
+
+    // Guard against an accidental select-all costing a fortune
+    let length = selected_text.trim().chars().count();
+    if !offline
+        && settings.confirm_large_input_chars > 0
+        && length > settings.confirm_large_input_chars
+        && !confirm_large_input(&app_handle, action, &selected_text, &settings).await
+    {
+        eprintln!("Cancelled {}-character selection", length);
+        return;
+    }
+
+    if !offline {
+        if let Err(e) = record_request(&settings) {
+            eprintln!("{}", e);
+            send_notification(&app_handle, settings.title("daily_limit"), &e, &settings);
+            return;
+        }
+    }
+
+    // Collapsing spaces would break the alignment of code, and offline
+    // actions get the text exactly as selected
+    let normalize = settings.normalize_input && !offline && action != TextAction::CodePolish;
+    let selected_text = if normalize {
+        normalize_text(&selected_text, &settings)
+    } else {
+        selected_text
+    };
+
+    *app_handle.state::<LastRequest>().0.lock().unwrap() =
+        Some((binding.clone(), selected_text.clone()));
+
+    let result = if offline {
+        match action.transform_locally(&selected_text, binding.case) {
+            // Safe mode never touches the clipboard or the selection on its own
+            Ok(text) if settings.preview_before_apply || settings.safe_mode => {
+                show_result_in_preview(&app_handle, &text);
+                return;
+            }
+            result => result.map(LlmOutput::local),
+        }
+    } else {
+        // Show processing state
+        update_tray_icon_processing(&app_handle, true);
+
+        let mut spec = action.prompt_spec(&settings, &selected_text);
+        if let Some(prompt) = &binding.prompt {
+            spec.prompt = prompt.clone();
+            spec.json_output = false; // A custom prompt won't ask for the JSON shape
+        }
+
+        // Safe mode never touches the clipboard or the selection on its own
+        if settings.preview_before_apply || settings.safe_mode {
+            preview_text_action(&app_handle, action, &selected_text, &spec, &settings).await;
+            update_tray_icon_processing(&app_handle, false);
+            return;
+        }
+
+        request_text_action(&app_handle, action, &selected_text, &spec, &settings).await
+    };
+
+    deliver_result(&app_handle, &binding, &selected_text, result, &settings).await;
+}
+
+// The request for an online action, followed by a translation's glossary and
+// polish stages or the language check
+async fn request_text_action(
+    app_handle: &tauri::AppHandle,
+    action: TextAction,
+    selected_text: &str,
+    spec: &PromptSpec<'_>,
+    settings: &Settings,
+) -> Result<LlmOutput, String> {
+    let start_notice = settings
+        .notify_on_start
+        .then(|| spawn_start_notification(app_handle, action, settings));
+    let result = process_text_with_llm(app_handle, selected_text, spec, settings, None).await;
+    let result = match result {
+        Ok(output) if action == TextAction::Translate => {
+            finish_translation(app_handle, output, settings).await
+        }
+        Ok(output)
+            if settings.verify_language_preserved
+                && action.edits_in_place()
+                && action != TextAction::CodePolish =>
+        {
+            Ok(verify_language(app_handle, selected_text, output, spec, settings).await)
+        }
+        result => result,
+    };
+    if let Some(start_notice) = start_notice {
+        start_notice.abort();
+    }
+    result
+}
+
+// Types or copies a finished result and reports it, or reports the failure.
+// Shared by shortcuts and creative retries.
+async fn deliver_result(
+    app_handle: &tauri::AppHandle,
+    binding: &ShortcutBinding,
+    selected_text: &str,
+    result: Result<LlmOutput, String>,
+    settings: &Settings,
+) {
+    let action = binding.action;
+    let from_clipboard = binding.from_clipboard;
+    match result {
+        Ok(output) => {
+            warn_if_truncated(app_handle, &output, settings);
+            warn_if_low_confidence(app_handle, &output, settings);
+            let details = output
+                .translation_details
+                .as_ref()
+                .map(|details| format!("\n{}", format_translation_details(details)))
+                .unwrap_or_default();
+            let cached = output.cached;
+            let fallback_provider = output.fallback_provider.clone();
+            let reduced_max_tokens = output.reduced_max_tokens;
+            let (result_text, explanation) = if action == TextAction::ImproveAndExplain {
+                split_explanation(&output.text)
+            } else {
+                (output.text, None)
+            };
+            record_result(settings, action, selected_text, &result_text);
+            let result_text =
+                apply_post_processing(result_text, settings, &PostStage::Result(action));
+            *app_handle.state::<LastResult>().0.lock().unwrap() = Some(result_text.clone());
+
+            // Type over the selection, falling back to the clipboard
+            let replaced = action.edits_in_place()
+                && settings.replace_mode
+                && !from_clipboard
+                && match replace_selection(app_handle, result_text.clone(), settings).await {
+                    Ok(()) => true,
+                    Err(e) => {
+                        eprintln!("Failed to replace selection: {}", e);
+                        send_notification(
+                            app_handle,
+                            settings.title("replace_failed"),
+                            &format!(
+                                "{}\nThe {} text was copied to the clipboard instead.",
+                                e,
+                                action.past_tense().to_lowercase()
+                            ),
+                            settings,
+                        );
+                        false
+                    }
+                };
+
+            let mut body = if replaced {
+                format!("{} text replaced the selection", action.past_tense())
+            } else {
+                // Clipboard input is replaced by the result alone
+                let clipboard_text = if action.edits_in_place() && !from_clipboard {
+                    format_clipboard(selected_text, &result_text, settings.clipboard_format)
+                } else {
+                    result_text.clone()
+                };
+
+                // Collect results after the existing clipboard text
+                let existing = (settings.append_to_clipboard && !from_clipboard)
+                    .then(|| app_handle.clipboard_manager().read_text().ok().flatten())
+                    .flatten()
+                    .filter(|existing| !existing.is_empty());
+                let appended = existing.is_some();
+                let combined_text = match existing {
+                    Some(existing) => {
+                        format!(
+                            "{}{}{}",
+                            existing, settings.clipboard_separator, clipboard_text
+                        )
+                    }
+                    None => clipboard_text.clone(),
+                };
+
+                // Copy to clipboard
+                if let Err(e) = write_clipboard(app_handle, &combined_text, settings).await {
+                    if settings.sound_enabled {
+                        play_error_sound();
+                    }
+                    let mut message = format!(
+                        "{}\nThe {} text could not be copied.",
+                        e,
+                        action.past_tense().to_lowercase()
+                    );
+                    if settings.show_result_on_copy_failure {
+                        show_result_in_preview(app_handle, &combined_text);
+                        message.push_str(" It is shown in the preview window instead.");
+                    }
+                    notify_error(
+                        app_handle,
+                        Some(action),
+                        settings.title("copy_failed"),
+                        &message,
+                        settings,
+                    );
+                    update_tray_icon_processing(app_handle, false);
+                    return;
+                }
+
+                format!(
+                    "{} text {} clipboard:\n{}",
+                    action.past_tense(),
+                    if appended { "appended to" } else { "copied to" },
+                    notification_preview(&clipboard_text)
+                )
+            };
+
+            // Show completion feedback
+            if settings.sound_enabled {
+                play_completion_sound(settings.action_sound(action));
+            }
+
+            body.push_str(&details);
+            match explanation {
+                Some(explanation) => body.push_str(&format!("\nChanges:\n{}", explanation)),
+                None if action == TextAction::ImproveAndExplain => {
+                    body.push_str("\nThe model did not explain its changes.")
+                }
+                None => {}
+            }
+            if cached {
+                body.push_str("\n(cached, no request was made)");
+            }
+            if let Some(provider) = fallback_provider {
+                body.push_str(&format!(
+                    "\n{} was unavailable, answered by {}",
+                    settings.provider, provider
+                ));
+            }
+            if let Some(max_tokens) = reduced_max_tokens {
+                body.push_str(&format!(
+                    "\nThe text was too long for the model's context, so the output was limited to {} tokens",
+                    max_tokens
+                ));
+            }
+            if settings.show_stats_in_notification {
+                body.push_str(&format!(
+                    "\n{}",
+                    format_text_stats(action.past_tense(), selected_text, &result_text)
+                ));
+            }
+
+            let title = settings.action_title(action, "done", action.done_title());
+            // The list of changes isn't shown anywhere else, so it is posted
+            // even when success notifications are off
+            if action == TextAction::ImproveAndExplain {
+                post_notification(
+                    app_handle,
+                    title,
+                    &body,
+                    settings.notification_urgency,
+                    settings,
+                );
+            } else {
+                show_notification(
+                    app_handle,
+                    title,
+                    &body,
+                    settings.notification_urgency,
+                    settings,
+                );
+            }
+        }
+        Err(e) => {
+            let mut message = format!("Failed to {} text: {}", action.verb(), e);
+            if is_retryable_error(&e)
+                && !settings.retry_shortcut.is_empty()
+                && offer_retry(app_handle, settings)
+            {
+                message.push_str(&format!(
+                    "\nPress {} within {} seconds to try again.",
+                    settings.retry_shortcut,
+                    RETRY_OFFER_WINDOW.as_secs()
+                ));
+            }
+            notify_error(
+                app_handle,
+                Some(action),
+                settings.action_title(action, "failed", action.failed_title()),
+                &message,
+                settings,
+            );
+        }
+    }
+
+    // Reset processing state
+    update_tray_icon_processing(app_handle, false);
+}
+
+// Clears everything kept between requests, for when the app seems stuck and a
+// restart would otherwise be needed. Returns what was cleared.
+#[tauri::command]
+fn reset_runtime_state(app_handle: tauri::AppHandle) -> Vec<String> {
+    let mut cleared = Vec::new();
+
+    let cached = RESULT_CACHE
+        .lock()
+        .unwrap()
+        .take()
+        .map_or(0, |entries| entries.len());
+    let cache_path = get_cache_path();
+    if cache_path.exists() {
+        if let Err(e) = fs::remove_file(&cache_path) {
+            eprintln!("Failed to delete {}: {}", cache_path.display(), e);
+        }
+    }
+    cleared.push(format!("{} cached results", cached));
+
+    if app_handle
+        .state::<LastResult>()
+        .0
+        .lock()
+        .unwrap()
+        .take()
+        .is_some()
+    {
+        cleared.push("the last result".to_string());
+    }
+    if app_handle
+        .state::<LastRequest>()
+        .0
+        .lock()
+        .unwrap()
+        .take()
+        .is_some()
+    {
+        cleared.push("the last request".to_string());
+    }
+    if app_handle
+        .state::<RetryOffer>()
+        .0
+        .lock()
+        .unwrap()
+        .shortcut
+        .is_some()
+    {
+        withdraw_retry_offer(&app_handle, None);
+        cleared.push("the retry offer".to_string());
+    }
+    // Dropping the sender cancels the waiting action
+    if app_handle
+        .state::<Confirm>()
+        .0
+        .lock()
+        .unwrap()
+        .take()
+        .is_some()
+    {
+        cleared.push("a pending confirmation".to_string());
+    }
+    if let Some(window) = app_handle.get_window("confirm") {
+        let _ = window.close();
+    }
+    app_handle
+        .state::<ShortcutDebounce>()
+        .0
+        .lock()
+        .unwrap()
+        .clear();
+    *app_handle.state::<CaseCycle>().0.lock().unwrap() = None;
+    if app_handle
+        .state::<LastError>()
+        .0
+        .lock()
+        .unwrap()
+        .take()
+        .is_some()
+    {
+        cleared.push("the last error".to_string());
+    }
+    update_tray_icon_processing(&app_handle, false);
+    cleared.push("the processing indicator".to_string());
+
+    eprintln!("Reset runtime state: {}", cleared.join(", "));
+    cleared
+}
+
+#[tokio::main]
+async fn main() {
+    // A second launch would register the same shortcuts and add another tray icon
+    let mut instance_listener = None;
+    if load_settings().single_instance {
+        match acquire_instance_lock() {
+            Ok(Some(listener)) => instance_listener = Some(listener),
+            Ok(None) => {
+                eprintln!("Already running, showing its settings window instead");
+                return;
+            }
+            Err(e) => eprintln!("{}; not checking for another instance", e),
+        }
+    }
+    let instance_port = instance_listener
+        .as_ref()
+        .and_then(|listener| listener.local_addr().ok())
+        .map(|address| address.port());
+
+    // Registration state is unknown until setup runs, which refreshes the menu
+    let tray_menu = build_tray_menu(&load_settings(), &|_| true);
+    let mut system_tray = SystemTray::new().with_menu(tray_menu);
+    let context = tauri::generate_context!();
+    if context.system_tray_icon().is_none() {
+        eprintln!("Warning: no tray icon configured, using the built-in one");
+        system_tray = system_tray.with_icon(fallback_tray_icon());
+    }
 
     tauri::Builder::default()
         .invoke_handler(tauri::generate_handler![
             save_settings,
             load_settings,
             get_api_key_for_provider,
-            save_api_key_for_provider
+            save_api_key_for_provider,
+            delete_api_key_for_provider,
+            list_configured_providers,
+            supported_providers,
+            provider_capabilities,
+            add_few_shot_example,
+            remove_few_shot_example,
+            polish_batch,
+            compare_providers,
+            run_prompt,
+            estimate_tokens,
+            reload_settings,
+            copy_last_result,
+            regenerate_creative,
+            export_settings,
+            import_settings,
+            get_today_count,
+            list_pinned_reviews,
+            get_last_error,
+            clear_last_error,
+            get_preview_state,
+            accept_preview,
+            get_confirm_state,
+            answer_confirm,
+            get_health,
+            preview_request,
+            reset_runtime_state,
+            open_config_dir
         ])
+        .manage(LastResult::default())
+        .manage(LastRequest::default())
+        .manage(Preview::default())
+        .manage(Confirm::default())
+        .manage(ShortcutDebounce::default())
+        .manage(RetryOffer::default())
+        .manage(RequestLimit::default())
+        .manage(CaseCycle::default())
+        .manage(LastError::default())
+        .manage(InstanceLock(instance_port))
         .system_tray(system_tray)
         .on_system_tray_event(|app, event| {
             if let SystemTrayEvent::MenuItemClick { id, .. } = event {
                 match id.as_str() {
-                    "settings" => {
-                        if let Some(window) = app.get_window("settings") {
-                            window.show().unwrap();
-                            window.set_focus().unwrap();
-                        } else {
-                            tauri::WindowBuilder::new(
+                    "settings" => open_settings_window(app),
+                    "open_config_dir" => {
+                        if let Err(e) = open_config_dir() {
+                            eprintln!("{}", e);
+                            let settings = load_settings();
+                            send_notification(
                                 app,
-                                "settings",
-                                tauri::WindowUrl::App("index.html".into()),
-                            )
-                            .title("Polish Language - Settings")
-                            .inner_size(500.0, 600.0)
-                            .resizable(false)
-                            .build()
-                            .unwrap();
+                                settings.title("open_config_dir_failed"),
+                                &e,
+                                &settings,
+                            );
                         }
                     }
                     "quit" => {
+                        release_instance_lock(&app.state::<InstanceLock>());
                         app.exit(0);
                     }
-                    _ => {}
+                    id => {
+                        if let Some(language) = id.strip_prefix(TRANSLATE_MENU_PREFIX) {
+                            tauri::async_runtime::spawn(handle_text_action(
+                                app.clone(),
+                                translate_to_binding(language),
+                            ));
+                        } else if let Some(case) = id
+                            .strip_prefix(CASE_MENU_PREFIX)
+                            .and_then(CaseMode::from_id)
+                        {
+                            tauri::async_runtime::spawn(handle_text_action(
+                                app.clone(),
+                                convert_case_binding(case),
+                            ));
+                        }
+                    }
                 }
             }
         })
-        .setup(|app| {
+        .setup(move |app| {
             // Hide dock icon on macOS
             #[cfg(target_os = "macos")]
             unsafe {
@@ -533,157 +6304,35 @@ async fn main() {
             let app_handle = app.handle();
             let settings = load_settings();
 
-            // Register the polish text global shortcut
-            let polish_shortcut = settings.shortcut.clone();
-            let app_handle_polish = app_handle.clone();
-            app.global_shortcut_manager()
-                .register(&polish_shortcut, move || {
-                    let app_handle_clone = app_handle_polish.clone();
-                    tauri::async_runtime::spawn(async move {
-                        let selected_text = match get_selected_text() {
-                            Ok(text) => text,
-                            Err(e) => {
-                                eprintln!("Error getting selected text: {:?}", e);
-                                return;
-                            }
-                        };
-
-                        if selected_text.trim().is_empty() {
-                            return;
-                        }
-
-                        let settings = load_settings();
-                        if settings.get_current_api_key().is_empty() {
-                            eprintln!("API key not configured for provider: {}", settings.provider);
-                            return;
-                        }
-
-                        // Show processing state
-                        update_tray_icon_processing(&app_handle_clone, true);
-
-                        match polish_text_with_llm(&selected_text, &settings).await {
-                            Ok(polished_text) => {
-                                // Copy to clipboard
-                                if app_handle_clone
-                                    .clipboard_manager()
-                                    .write_text(polished_text.clone())
-                                    .is_err()
-                                {
-                                    eprintln!("Failed to write to clipboard");
-                                }
-
-                                // Show completion feedback
-                                if settings.sound_enabled {
-                                    play_completion_sound();
-                                }
-
-                                let preview = if polished_text.len() > 100 {
-                                    format!("{}...", &polished_text[..97])
-                                } else {
-                                    polished_text
-                                };
-
-                                show_notification(
-                                    &app_handle_clone,
-                                    "Text Polished",
-                                    &format!("Polished text copied to clipboard:\n{}", preview),
-                                    &settings,
-                                );
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to polish text: {}", e);
-                                show_notification(
-                                    &app_handle_clone,
-                                    "Polish Failed",
-                                    &format!("Failed to polish text: {}", e),
-                                    &settings,
-                                );
-                            }
-                        }
+            register_shortcuts(&app_handle, &settings);
 
-                        // Reset processing state
-                        update_tray_icon_processing(&app_handle_clone, false);
-                    });
-                })
-                .unwrap_or_else(|e| eprintln!("Failed to register polish shortcut: {}", e));
-
-            // Register the translate text global shortcut
-            let translate_shortcut = settings.translate_shortcut.clone();
-            let app_handle_translate = app_handle.clone();
-            app.global_shortcut_manager()
-                .register(&translate_shortcut, move || {
-                    let app_handle_clone = app_handle_translate.clone();
-                    tauri::async_runtime::spawn(async move {
-                        let selected_text = match get_selected_text() {
-                            Ok(text) => text,
-                            Err(e) => {
-                                eprintln!("Error getting selected text: {:?}", e);
-                                return;
-                            }
-                        };
-
-                        if selected_text.trim().is_empty() {
-                            return;
-                        }
-
-                        let settings = load_settings();
-                        if settings.get_current_api_key().is_empty() {
-                            eprintln!("API key not configured for provider: {}", settings.provider);
-                            return;
-                        }
+            if let Some(listener) = instance_listener {
+                listen_for_instances(app_handle.clone(), listener);
+            }
 
-                        // Show processing state
-                        update_tray_icon_processing(&app_handle_clone, true);
-
-                        match translate_text_with_llm(&selected_text, &settings).await {
-                            Ok(translated_text) => {
-                                // Copy to clipboard
-                                if app_handle_clone
-                                    .clipboard_manager()
-                                    .write_text(translated_text.clone())
-                                    .is_err()
-                                {
-                                    eprintln!("Failed to write to clipboard");
-                                }
-
-                                // Show completion feedback
-                                if settings.sound_enabled {
-                                    play_completion_sound();
-                                }
-
-                                let preview = if translated_text.len() > 100 {
-                                    format!("{}...", &translated_text[..97])
-                                } else {
-                                    translated_text
-                                };
-
-                                show_notification(
-                                    &app_handle_clone,
-                                    "Text Translated",
-                                    &format!("Translated text copied to clipboard:\n{}", preview),
-                                    &settings,
-                                );
-                            }
-                            Err(e) => {
-                                eprintln!("Failed to translate text: {}", e);
-                                show_notification(
-                                    &app_handle_clone,
-                                    "Translation Failed",
-                                    &format!("Failed to translate text: {}", e),
-                                    &settings,
-                                );
-                            }
-                        }
+            // Report misconfiguration once at launch instead of failing silently on each press
+            let issues = check_health(&app_handle, &settings);
+            if !issues.is_empty() {
+                let body = issues
+                    .iter()
+                    .map(|issue| format!("• {}", issue.message))
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                send_notification(
+                    &app_handle,
+                    settings.title("needs_attention"),
+                    &body,
+                    &settings,
+                );
+            }
 
-                        // Reset processing state
-                        update_tray_icon_processing(&app_handle_clone, false);
-                    });
-                })
-                .unwrap_or_else(|e| eprintln!("Failed to register translate shortcut: {}", e));
+            if settings.watch_settings_file {
+                watch_settings_file(app_handle.clone());
+            }
             app.set_activation_policy(tauri::ActivationPolicy::Accessory);
             Ok(())
         })
-        .build(tauri::generate_context!())
+        .build(context)
         .expect("error while building tauri application")
         .run(|_app_handle, event| {
             if let tauri::RunEvent::ExitRequested { api, .. } = event {
@@ -691,3 +6340,545 @@ async fn main() {
             }
         });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Each test that touches the environment uses its own provider, so the
+    // variables don't clash when tests run in parallel
+    fn settings_for(provider: &str) -> Settings {
+        Settings {
+            provider: provider.to_string(),
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn env_var_overrides_stored_api_key() {
+        let mut settings = settings_for("envwins");
+        settings
+            .api_keys
+            .insert("envwins".to_string(), "stored-key".to_string());
+        std::env::set_var(api_key_env_var("envwins"), " env-key ");
+        assert_eq!(settings.get_current_api_key(), "env-key");
+    }
+
+    #[test]
+    fn empty_env_var_falls_back_to_stored_api_key() {
+        let mut settings = settings_for("envempty");
+        settings
+            .api_keys
+            .insert("envempty".to_string(), "stored-key".to_string());
+        std::env::set_var(api_key_env_var("envempty"), "  ");
+        assert_eq!(settings.get_current_api_key(), "stored-key");
+    }
+
+    #[test]
+    fn clean_response_strips_preamble_line() {
+        assert_eq!(
+            clean_response("Sure! Here's the polished text:\nThe meeting is at noon."),
+            "The meeting is at noon."
+        );
+        assert_eq!(
+            clean_response("Here is the revised version:\n\nThe meeting is at noon."),
+            "The meeting is at noon."
+        );
+    }
+
+    #[test]
+    fn clean_response_strips_quotes_around_whole_reply() {
+        assert_eq!(
+            clean_response("\"The meeting is at noon.\""),
+            "The meeting is at noon."
+        );
+        assert_eq!(
+            clean_response("\u{201C}The meeting is at noon.\u{201D}"),
+            "The meeting is at noon."
+        );
+        // Quotes that are part of the text stay
+        assert_eq!(
+            clean_response("\"Noon\" is when \"we\" meet."),
+            "\"Noon\" is when \"we\" meet."
+        );
+    }
+
+    #[test]
+    fn clean_response_strips_epilogue() {
+        assert_eq!(
+            clean_response("The meeting is at noon.\n\nLet me know if you need anything else!"),
+            "The meeting is at noon."
+        );
+    }
+
+    #[test]
+    fn clean_response_keeps_text_starting_with_here() {
+        let text = "Here is the plan. We ship on Friday.";
+        assert_eq!(clean_response(text), text);
+        let text = "Here's what happened yesterday.\nThe build broke twice.";
+        assert_eq!(clean_response(text), text);
+    }
+
+    #[test]
+    fn reasoning_model_request_uses_max_completion_tokens() {
+        for model in ["o1", "o3-mini"] {
+            let settings = Settings {
+                model: model.to_string(),
+                ..settings_for("openai")
+            };
+            let spec = TextAction::Proofread.prompt_spec(&settings, "Some text");
+            let body = build_openai_request("Some text", &spec, &settings, false).unwrap();
+            assert!(body.get("temperature").is_none(), "{}", model);
+            assert!(body.get("max_tokens").is_none(), "{}", model);
+            assert!(body["max_completion_tokens"].is_u64(), "{}", model);
+        }
+    }
+
+    #[test]
+    fn chat_model_request_uses_max_tokens() {
+        let settings = Settings {
+            model: "gpt-4o-mini".to_string(),
+            ..settings_for("openai")
+        };
+        let spec = TextAction::Proofread.prompt_spec(&settings, "Some text");
+        let body = build_openai_request("Some text", &spec, &settings, false).unwrap();
+        assert!(body["temperature"].is_number());
+        assert!(body["max_tokens"].is_u64());
+        assert!(body.get("max_completion_tokens").is_none());
+    }
+
+    fn migrated_fixture(json: &str) -> Settings {
+        let settings = parse_settings(json);
+        assert_eq!(settings.schema_version, SETTINGS_SCHEMA_VERSION);
+        settings
+    }
+
+    // Running the migration, or any one step, again must not change anything
+    fn assert_migration_is_idempotent(settings: &Settings) {
+        let expected = serde_json::to_value(settings).unwrap();
+        let mut again = settings.clone();
+        again.schema_version = 0;
+        again.migrate();
+        assert_eq!(serde_json::to_value(&again).unwrap(), expected);
+        for step in SETTINGS_MIGRATIONS {
+            let mut again = settings.clone();
+            step(&mut again);
+            assert_eq!(serde_json::to_value(&again).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn migrates_v0_settings() {
+        let settings = migrated_fixture(include_str!("../tests/fixtures/settings_v0.json"));
+        assert_eq!(settings.api_key, None);
+        assert_eq!(settings.api_keys.get("openai").unwrap(), "sk-legacy");
+        assert_eq!(settings.base_url, None);
+        assert_eq!(
+            settings.base_urls.get("openai").unwrap(),
+            "https://proxy.example.com/v1"
+        );
+        assert_eq!(settings.notifications_enabled, None);
+        assert!(!settings.notify_on_success);
+        assert!(!settings.notify_on_error);
+        assert_migration_is_idempotent(&settings);
+    }
+
+    #[test]
+    fn migrates_v1_settings() {
+        let settings = migrated_fixture(include_str!("../tests/fixtures/settings_v1.json"));
+        assert_eq!(settings.api_keys.len(), 1);
+        assert_eq!(settings.api_keys.get("openai").unwrap(), "sk-openai");
+        assert_eq!(settings.base_url, None);
+        assert_eq!(
+            settings.base_urls.get("gemini").unwrap(),
+            "https://gemini-proxy.example.com"
+        );
+        assert!(settings.notify_on_success);
+        assert!(settings.notify_on_error);
+        assert_migration_is_idempotent(&settings);
+    }
+
+    #[test]
+    fn migrates_v2_settings() {
+        let settings = migrated_fixture(include_str!("../tests/fixtures/settings_v2.json"));
+        assert_eq!(
+            settings.base_urls.get("openai").unwrap(),
+            "https://proxy.example.com/v1"
+        );
+        assert_eq!(settings.notifications_enabled, None);
+        assert!(!settings.notify_on_success);
+        assert!(!settings.notify_on_error);
+        assert_migration_is_idempotent(&settings);
+    }
+
+    // Answers with the queued results in order and counts the requests
+    struct MockProvider {
+        answers: Mutex<VecDeque<Result<String, String>>>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl MockProvider {
+        fn new(answers: Vec<Result<&str, &str>>) -> (Self, Arc<AtomicUsize>) {
+            let calls = Arc::new(AtomicUsize::new(0));
+            let answers = answers
+                .into_iter()
+                .map(|answer| answer.map(str::to_string).map_err(str::to_string))
+                .collect();
+            let provider = MockProvider {
+                answers: Mutex::new(answers),
+                calls: calls.clone(),
+            };
+            (provider, calls)
+        }
+    }
+
+    impl LlmProvider for MockProvider {
+        fn complete<'a>(
+            &'a self,
+            _request: LlmRequest<'a>,
+        ) -> BoxFuture<'a, Result<LlmOutput, String>> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            let answer = self
+                .answers
+                .lock()
+                .unwrap()
+                .pop_front()
+                .expect("no answer queued");
+            Box::pin(async move { answer.map(LlmOutput::local) })
+        }
+
+        fn prepare(
+            &self,
+            text: &str,
+            _spec: &PromptSpec<'_>,
+            _settings: &Settings,
+        ) -> Result<(String, Option<String>, serde_json::Value), String> {
+            Ok((
+                "mock://".to_string(),
+                None,
+                serde_json::json!({ "text": text }),
+            ))
+        }
+    }
+
+    fn mock_entry(id: &'static str, provider: MockProvider) -> ProviderEntry {
+        ProviderEntry {
+            id,
+            name: id,
+            default_base_url: "mock://",
+            default_model: "mock-model",
+            models: &[],
+            features: OPENAI_FEATURES,
+            api: Box::new(provider),
+        }
+    }
+
+    // Settings for the "mock" provider, falling back to "backup"
+    fn mock_settings() -> Settings {
+        let mut settings = settings_for("mock");
+        settings.fallback_providers = vec!["backup".to_string()];
+        settings
+            .api_keys
+            .insert("mock".to_string(), "key".to_string());
+        settings
+            .api_keys
+            .insert("backup".to_string(), "key".to_string());
+        settings
+    }
+
+    async fn process_with_mocks(
+        primary: MockProvider,
+        backup: MockProvider,
+        settings: &Settings,
+    ) -> Result<LlmOutput, String> {
+        let mut registry = ProviderRegistry(Vec::new());
+        registry.register(mock_entry("mock", primary));
+        registry.register(mock_entry("backup", backup));
+        let spec = TextAction::Proofread.prompt_spec(settings, "Some text");
+        process_text_with_providers(
+            &registry,
+            Some(&RequestLimit::default()),
+            "Some text",
+            &spec,
+            settings,
+            None,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn falls_back_when_the_provider_is_down() {
+        let (primary, primary_calls) =
+            MockProvider::new(vec![Err("API request failed with status: 503")]);
+        let (backup, backup_calls) = MockProvider::new(vec![Ok("Fixed text")]);
+        let output = process_with_mocks(primary, backup, &mock_settings())
+            .await
+            .unwrap();
+        assert_eq!(output.text, "Fixed text");
+        assert_eq!(output.fallback_provider.as_deref(), Some("backup"));
+        assert_eq!(primary_calls.load(Ordering::SeqCst), 1);
+        assert_eq!(backup_calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn does_not_fall_back_on_request_errors() {
+        let (primary, _) = MockProvider::new(vec![Err("API request failed with status: 401")]);
+        let (backup, backup_calls) = MockProvider::new(vec![Ok("Fixed text")]);
+        let error = process_with_mocks(primary, backup, &mock_settings())
+            .await
+            .err()
+            .expect("the request should fail");
+        assert!(error.contains("status: 401"));
+        assert!(is_auth_error(&error));
+        assert_eq!(backup_calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn retries_empty_answers_once() {
+        let (primary, calls) = MockProvider::new(vec![Err(EMPTY_CONTENT_ERROR), Ok("Fixed text")]);
+        let (backup, _) = MockProvider::new(vec![]);
+        let output = process_with_mocks(primary, backup, &mock_settings())
+            .await
+            .unwrap();
+        assert_eq!(output.text, "Fixed text");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn halves_max_tokens_after_a_context_length_error() {
+        let (primary, _) = MockProvider::new(vec![
+            Err("API request failed with status: 400 context_length_exceeded"),
+            Ok("Fixed text"),
+        ]);
+        let (backup, _) = MockProvider::new(vec![]);
+        let settings = mock_settings();
+        let spec = TextAction::Proofread.prompt_spec(&settings, "Some text");
+        let output = process_with_mocks(primary, backup, &settings)
+            .await
+            .unwrap();
+        assert_eq!(
+            output.reduced_max_tokens,
+            Some(spec.max_tokens(&settings) / 2)
+        );
+    }
+
+    #[tokio::test]
+    async fn unknown_provider_is_an_error() {
+        let (primary, _) = MockProvider::new(vec![]);
+        let (backup, _) = MockProvider::new(vec![]);
+        let settings = settings_for("missing");
+        let error = process_with_mocks(primary, backup, &settings)
+            .await
+            .err()
+            .expect("the request should fail");
+        assert_eq!(error, "Unknown provider: missing");
+    }
+
+    // Both stages, as a polish result goes through them
+    fn post_process(settings: &Settings, answer: &str, redactions: &[String]) -> String {
+        let answer = apply_post_processing(
+            answer.to_string(),
+            settings,
+            &PostStage::Answer {
+                redactions,
+                code: false,
+            },
+        );
+        apply_post_processing(answer, settings, &PostStage::Result(TextAction::Polish))
+    }
+
+    fn pipeline_settings(steps: Vec<PostProcessor>) -> Settings {
+        Settings {
+            post_processors: steps,
+            strip_preamble: true,
+            result_template: Some("\"{result}\"".to_string()),
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn post_processors_run_in_the_configured_order() {
+        // Stripping before the template keeps the template's quotes
+        let settings =
+            pipeline_settings(vec![PostProcessor::StripPreamble, PostProcessor::Template]);
+        assert_eq!(post_process(&settings, "\"Hello\"", &[]), "\"Hello\"");
+        // Stripping after it removes them again
+        let settings =
+            pipeline_settings(vec![PostProcessor::Template, PostProcessor::StripPreamble]);
+        assert_eq!(post_process(&settings, "Hello", &[]), "Hello");
+    }
+
+    #[test]
+    fn wrap_after_template_wraps_the_filled_in_template() {
+        let mut settings = pipeline_settings(vec![PostProcessor::Template, PostProcessor::Wrap]);
+        settings.result_template = Some("Greetings to everyone, {result}".to_string());
+        settings.wrap_width = Some(20);
+        assert_eq!(
+            post_process(&settings, "see you soon", &[]),
+            "Greetings to\neveryone, see you\nsoon"
+        );
+        settings.post_processors = vec![PostProcessor::Wrap, PostProcessor::Template];
+        assert_eq!(
+            post_process(&settings, "see you soon", &[]),
+            "Greetings to everyone, see you soon"
+        );
+    }
+
+    #[test]
+    fn post_processors_left_out_are_skipped() {
+        let mut settings = pipeline_settings(vec![PostProcessor::Template]);
+        settings.wrap_width = Some(5);
+        let redactions = ["secret@example.com".to_string()];
+        assert_eq!(
+            post_process(
+                &settings,
+                "Sure, here it is:\nMail [REDACTED_1]",
+                &redactions
+            ),
+            "\"Sure, here it is:\nMail [REDACTED_1]\""
+        );
+        settings.post_processors = Vec::new();
+        assert_eq!(post_process(&settings, " Hello ", &[]), " Hello ");
+    }
+
+    #[test]
+    fn unmasking_runs_on_the_answer_wherever_it_is_listed() {
+        let settings = pipeline_settings(vec![
+            PostProcessor::Template,
+            PostProcessor::UnmaskRedactions,
+        ]);
+        let redactions = ["secret@example.com".to_string()];
+        let answer = apply_post_processing(
+            "Mail [REDACTED_1]".to_string(),
+            &settings,
+            &PostStage::Answer {
+                redactions: &redactions,
+                code: false,
+            },
+        );
+        assert_eq!(answer, "Mail secret@example.com");
+        assert_eq!(
+            post_process(&settings, "Mail [REDACTED_1]", &redactions),
+            "\"Mail secret@example.com\""
+        );
+    }
+
+    #[test]
+    fn prepared_text_is_masked() {
+        let settings = Settings {
+            redact_patterns: vec![r"\S+@\S+".to_string()],
+            ..Settings::default()
+        };
+        let spec = TextAction::Polish.prompt_spec(&settings, "");
+        let prepared = prepare_text("Mail me at sam@example.com", &spec, &settings).unwrap();
+        assert_eq!(prepared.text, "Mail me at [REDACTED_1]");
+        assert_eq!(prepared.redactions, ["sam@example.com"]);
+    }
+
+    #[test]
+    fn prepared_code_is_sent_without_its_fence() {
+        let settings = Settings::default();
+        let spec = TextAction::CodePolish.prompt_spec(&settings, "");
+        let prepared = prepare_text("```rust\nlet x = 1;\n```", &spec, &settings).unwrap();
+        assert_eq!(prepared.text, "let x = 1;");
+        assert_eq!(prepared.fence_info, Some("rust"));
+    }
+
+    #[test]
+    fn glossary_replaces_whole_words_only() {
+        let mut settings = Settings::default();
+        settings
+            .glossary
+            .insert("AI".to_string(), "A.I.".to_string());
+        settings
+            .glossary
+            .insert("C++".to_string(), "C plus plus".to_string());
+        assert_eq!(
+            enforce_glossary("AI is PAID by EMAIL, AI.", &settings),
+            "A.I. is PAID by EMAIL, A.I.."
+        );
+        assert_eq!(
+            enforce_glossary("We use C++ daily.", &settings),
+            "We use C plus plus daily."
+        );
+        // The cached patterns follow glossary changes
+        settings
+            .glossary
+            .insert("PAID".to_string(), "settled".to_string());
+        assert_eq!(enforce_glossary("PAID", &settings), "settled");
+    }
+
+    #[test]
+    fn cache_key_covers_extra_body_and_headers() {
+        let settings = settings_for("openai");
+        let spec = TextAction::Polish.prompt_spec(&settings, "text");
+        let key = result_cache_key("text", &spec, &settings);
+        assert_eq!(key, result_cache_key("text", &spec, &settings));
+        assert_eq!(key.len(), 64);
+
+        let mut with_body = settings.clone();
+        with_body
+            .extra_body
+            .insert("openai".to_string(), serde_json::json!({"seed": 1}));
+        assert_ne!(key, result_cache_key("text", &spec, &with_body));
+
+        let mut with_header = settings.clone();
+        with_header.extra_headers.insert(
+            "openai".to_string(),
+            HashMap::from([("X-Title".to_string(), "Polish".to_string())]),
+        );
+        assert_ne!(key, result_cache_key("text", &spec, &with_header));
+    }
+
+    #[tokio::test]
+    async fn stream_keeps_characters_split_between_chunks() {
+        let event =
+            "data: {\"choices\":[{\"delta\":{\"content\":\"Grüße 你好\"}}]}\n\ndata: [DONE]\n";
+        // Every chunk boundary falls inside a multi-byte character somewhere
+        let chunks: Vec<Result<Vec<u8>, String>> = event
+            .as_bytes()
+            .chunks(1)
+            .map(|byte| Ok(byte.to_vec()))
+            .collect();
+        let streamed = Mutex::new(String::new());
+        let on_chunk = |chunk: &str| streamed.lock().unwrap().push_str(chunk);
+        let output = read_openai_events(stream::iter(chunks), &settings_for("openai"), &on_chunk)
+            .await
+            .expect("the stream should parse");
+        assert_eq!(output.text, "Grüße 你好");
+        assert_eq!(*streamed.lock().unwrap(), "Grüße 你好");
+    }
+
+    #[test]
+    fn errors_hide_provider_and_fallback_keys() {
+        let mut settings = settings_for("gemini");
+        settings
+            .api_keys
+            .insert("gemini".to_string(), "gemini-secret".to_string());
+        settings
+            .api_keys
+            .insert("openai".to_string(), "openai-secret".to_string());
+        settings.fallback_providers = vec!["openai".to_string()];
+        let error = "Request failed: error sending request for url \
+            (https://generativelanguage.googleapis.com/v1beta/models/gemini-pro:generateContent?key=gemini-secret); \
+            fallback: invalid key openai-secret";
+        let redacted = redact_error(error, &settings);
+        assert!(!redacted.contains("gemini-secret"));
+        assert!(!redacted.contains("openai-secret"));
+        assert!(redacted.contains(&format!("?key={}", REDACTED)));
+    }
+
+    #[test]
+    fn normalize_text_straightens_quotes_only_when_asked() {
+        let text = "  \u{201C}Don\u{2019}t\u{201D}\u{00A0}  stop  ";
+        let mut settings = Settings::default();
+        assert_eq!(
+            normalize_text(text, &settings),
+            "  \u{201C}Don\u{2019}t\u{201D} stop"
+        );
+        settings.straighten_quotes = true;
+        assert_eq!(normalize_text(text, &settings), "  \"Don't\" stop");
+    }
+}
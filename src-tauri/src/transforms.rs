@@ -0,0 +1,513 @@
+// Offline transforms of the selected text, for actions that don't need a provider
+
+use base64::alphabet;
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig, STANDARD};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use heck::{ToKebabCase, ToLowerCamelCase, ToSnakeCase};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+// Decoding accepts input with or without padding
+const DECODE_CONFIG: GeneralPurposeConfig =
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent);
+const STANDARD_DECODER: GeneralPurpose = GeneralPurpose::new(&alphabet::STANDARD, DECODE_CONFIG);
+const URL_SAFE_DECODER: GeneralPurpose = GeneralPurpose::new(&alphabet::URL_SAFE, DECODE_CONFIG);
+
+// Everything except RFC 3986's unreserved characters, so the result is safe
+// anywhere in a URL
+const URL_COMPONENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~');
+
+// The offline tidy action: converts line endings to \n, strips trailing
+// whitespace, collapses runs of blank lines into one and drops blank lines at
+// the start and end. Nothing else changes, including indentation.
+pub fn tidy_text(text: &str) -> String {
+    let text = text.replace("\r\n", "\n").replace('\r', "\n");
+    let mut lines: Vec<&str> = Vec::new();
+    for line in text.lines().map(str::trim_end) {
+        if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    if lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+pub fn base64_encode(text: &str) -> Result<String, String> {
+    Ok(STANDARD.encode(text))
+}
+
+// Accepts the standard and URL-safe alphabets and ignores line breaks, as in
+// wrapped PEM or MIME bodies
+pub fn base64_decode(text: &str) -> Result<String, String> {
+    let compact: String = text.chars().filter(|c| !c.is_whitespace()).collect();
+    if compact.is_empty() {
+        return Err("Nothing to decode".to_string());
+    }
+    let bytes = STANDARD_DECODER
+        .decode(&compact)
+        .or_else(|_| URL_SAFE_DECODER.decode(&compact))
+        .map_err(|e| format!("Not valid Base64: {}", e))?;
+    String::from_utf8(bytes).map_err(|_| "The decoded data is not valid UTF-8 text".to_string())
+}
+
+pub fn url_encode(text: &str) -> Result<String, String> {
+    Ok(utf8_percent_encode(text, URL_COMPONENT).to_string())
+}
+
+// `+` is left as is, since it only means a space in form-encoded query strings
+pub fn url_decode(text: &str) -> Result<String, String> {
+    percent_decode_str(text.trim())
+        .decode_utf8()
+        .map(|decoded| decoded.into_owned())
+        .map_err(|_| "The decoded data is not valid UTF-8 text".to_string())
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum CaseMode {
+    Upper,
+    Lower,
+    Title,
+    Camel,
+    Snake,
+    Kebab,
+}
+
+impl CaseMode {
+    // The order the cycling shortcut goes through
+    pub const ALL: [CaseMode; 6] = [
+        CaseMode::Upper,
+        CaseMode::Lower,
+        CaseMode::Title,
+        CaseMode::Camel,
+        CaseMode::Snake,
+        CaseMode::Kebab,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            CaseMode::Upper => "UPPER CASE",
+            CaseMode::Lower => "lower case",
+            CaseMode::Title => "Title Case",
+            CaseMode::Camel => "camelCase",
+            CaseMode::Snake => "snake_case",
+            CaseMode::Kebab => "kebab-case",
+        }
+    }
+
+    pub fn id(self) -> &'static str {
+        match self {
+            CaseMode::Upper => "upper",
+            CaseMode::Lower => "lower",
+            CaseMode::Title => "title",
+            CaseMode::Camel => "camel",
+            CaseMode::Snake => "snake",
+            CaseMode::Kebab => "kebab",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<CaseMode> {
+        CaseMode::ALL.into_iter().find(|mode| mode.id() == id)
+    }
+
+    pub fn next(self) -> CaseMode {
+        let index = CaseMode::ALL
+            .iter()
+            .position(|mode| *mode == self)
+            .unwrap_or(0);
+        CaseMode::ALL[(index + 1) % CaseMode::ALL.len()]
+    }
+}
+
+// UPPER, lower and Title Case keep the text's spacing and punctuation. The
+// identifier cases turn each line into one identifier, so a list of names can
+// be converted at once.
+pub fn convert_case(text: &str, mode: CaseMode) -> String {
+    match mode {
+        CaseMode::Upper => text.to_uppercase(),
+        CaseMode::Lower => text.to_lowercase(),
+        CaseMode::Title => title_case(text),
+        CaseMode::Camel => map_lines(text, |line| line.to_lower_camel_case()),
+        CaseMode::Snake => map_lines(text, |line| line.to_snake_case()),
+        CaseMode::Kebab => map_lines(text, |line| line.to_kebab_case()),
+    }
+}
+
+// Capitalizes the first letter of each word and lowercases the rest
+fn title_case(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut word_start = true;
+    for c in text.chars() {
+        if word_start {
+            result.extend(c.to_uppercase());
+        } else {
+            result.extend(c.to_lowercase());
+        }
+        word_start = c.is_whitespace();
+    }
+    result
+}
+
+fn map_lines(text: &str, convert: impl Fn(&str) -> String) -> String {
+    text.lines().map(convert).collect::<Vec<_>>().join("\n")
+}
+
+// Escaped characters are swapped for private-use code points while the inline
+// syntax is stripped, so `\*` isn't taken for emphasis
+const ESCAPE_BASE: u32 = 0xF0000;
+
+// Markdown to plain text, for fields that don't render it. Headings, emphasis,
+// quotes, rules and table borders are dropped; list items keep their
+// indentation with a `•` bullet or their number; links keep their URL in
+// parentheses. Fenced code blocks keep their content, without the fences.
+pub fn strip_markdown(text: &str) -> String {
+    let inline = InlinePatterns::new();
+    let mut lines: Vec<String> = Vec::new();
+    let mut fence: Option<(char, usize)> = None;
+    for line in text.lines() {
+        let trimmed = line.trim();
+        if let Some((marker, length)) = fence {
+            if fence_marker(trimmed)
+                .is_some_and(|(c, n)| c == marker && n >= length && trimmed.chars().all(|t| t == c))
+            {
+                fence = None;
+            } else {
+                lines.push(line.trim_end().to_string());
+            }
+            continue;
+        }
+        if let Some(marker) = fence_marker(trimmed) {
+            fence = Some(marker);
+            continue;
+        }
+        if is_rule(trimmed) {
+            continue;
+        }
+        let Some(line) = strip_block(line, &inline) else {
+            continue;
+        };
+        // Runs of blank lines mean the same as one outside code blocks
+        if line.is_empty() && lines.last().is_none_or(|last| last.is_empty()) {
+            continue;
+        }
+        lines.push(line);
+    }
+    while lines.last().is_some_and(|last| last.is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+// The character and length of a ``` or ~~~ fence
+fn fence_marker(line: &str) -> Option<(char, usize)> {
+    let marker = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let length = line.chars().take_while(|c| *c == marker).count();
+    (length >= 3).then_some((marker, length))
+}
+
+// Thematic breaks, and the `===` and `---` lines under setext headings
+fn is_rule(line: &str) -> bool {
+    let compact: Vec<char> = line.chars().filter(|c| !c.is_whitespace()).collect();
+    match compact.first() {
+        Some('=') => compact.iter().all(|c| *c == '='),
+        Some(marker @ ('-' | '*' | '_')) => {
+            compact.len() >= 3 && compact.iter().all(|c| c == marker)
+        }
+        _ => false,
+    }
+}
+
+// None for lines that only carry markup
+fn strip_block(line: &str, inline: &InlinePatterns) -> Option<String> {
+    let indent = &line[..line.len() - line.trim_start().len()];
+    let mut rest = line.trim();
+    let mut quoted = false;
+    while let Some(inner) = rest.strip_prefix('>') {
+        rest = inner.trim_start();
+        quoted = true;
+    }
+    // Nesting inside a quote can't be told apart from the quote's own indent
+    let indent = if quoted { "" } else { indent };
+
+    if let Some(captures) = inline.heading.captures(rest) {
+        return Some(strip_inline(
+            captures.get(1).map_or("", |m| m.as_str()),
+            inline,
+        ));
+    }
+    if rest.starts_with('|') {
+        // The row under the header only sets the alignment
+        if rest.chars().all(|c| matches!(c, '|' | ':' | '-' | ' ')) {
+            return None;
+        }
+        return Some(
+            split_table_row(rest)
+                .iter()
+                .map(|cell| strip_inline(cell.trim(), inline))
+                .collect::<Vec<_>>()
+                .join("\t"),
+        );
+    }
+    if let Some(captures) = inline.list_item.captures(rest) {
+        let marker = &captures[1];
+        let bullet = if marker.ends_with(['.', ')']) {
+            format!("{}.", &marker[..marker.len() - 1])
+        } else {
+            "•".to_string()
+        };
+        return Some(format!(
+            "{}{} {}",
+            indent,
+            bullet,
+            strip_inline(&captures[2], inline)
+        ));
+    }
+    if rest.is_empty() {
+        return Some(String::new());
+    }
+    Some(format!("{}{}", indent, strip_inline(rest, inline)))
+}
+
+// Splits on the pipes between cells, leaving escaped ones and those in code
+// spans in the text
+fn split_table_row(row: &str) -> Vec<&str> {
+    let row = row.strip_prefix('|').unwrap_or(row);
+    let row = row
+        .strip_suffix('|')
+        .filter(|r| !r.ends_with('\\'))
+        .unwrap_or(row);
+    let mut cells = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut in_code = false;
+    for (i, c) in row.char_indices() {
+        match c {
+            '\\' => escaped = !escaped,
+            '`' => {
+                in_code = !in_code;
+                escaped = false;
+            }
+            '|' if !escaped && !in_code => {
+                cells.push(&row[start..i]);
+                start = i + 1;
+            }
+            _ => escaped = false,
+        }
+    }
+    cells.push(&row[start..]);
+    cells
+}
+
+struct InlinePatterns {
+    heading: Regex,
+    list_item: Regex,
+    image: Regex,
+    link: Regex,
+    autolink: Regex,
+    emphasis: [(Regex, &'static str); 5],
+}
+
+impl InlinePatterns {
+    fn new() -> Self {
+        let pattern = |pattern: &str| Regex::new(pattern).unwrap();
+        InlinePatterns {
+            heading: pattern(r"^#{1,6}(?:\s+(.*?))?(?:\s+#+)?\s*$"),
+            list_item: pattern(r"^([-*+]|\d{1,9}[.)])\s+(.*)$"),
+            image: pattern(r#"!\[([^\]]*)\]\([^)\s]*(?:\s+"[^"]*")?\)"#),
+            link: pattern(r#"\[([^\]]+)\]\(([^)\s]+)(?:\s+"[^"]*")?\)"#),
+            autolink: pattern(r"<((?:https?|mailto):[^>\s]+)>"),
+            // Bold before italic, so `***both***` loses all its asterisks.
+            // Underscores and single asterisks only count at word
+            // boundaries, as in snake_case and 2*3*4.
+            emphasis: [
+                (pattern(r"\*\*(\S(?:.*?\S)?)\*\*"), "$1"),
+                (pattern(r"(^|\W)__(\S(?:.*?\S)?)__(\W|$)"), "$1$2$3"),
+                (pattern(r"~~(\S(?:.*?\S)?)~~"), "$1"),
+                (pattern(r"(^|\W)\*(\S(?:[^*]*?\S)?)\*(\W|$)"), "$1$2$3"),
+                (pattern(r"(^|\W)_(\S(?:[^_]*?\S)?)_(\W|$)"), "$1$2$3"),
+            ],
+        }
+    }
+}
+
+// Code spans keep their content as is; the rest loses its inline syntax
+fn strip_inline(text: &str, inline: &InlinePatterns) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(open) = rest.find('`') {
+        let ticks = rest[open..].chars().take_while(|c| *c == '`').count();
+        let content_start = open + ticks;
+        let Some(close) = find_backtick_run(&rest[content_start..], ticks) else {
+            break;
+        };
+        result.push_str(&strip_inline_syntax(&rest[..open], inline));
+        let code = &rest[content_start..content_start + close];
+        let code = match code.strip_prefix(' ').and_then(|c| c.strip_suffix(' ')) {
+            Some(inner) if !inner.trim().is_empty() => inner,
+            _ => code,
+        };
+        result.push_str(code);
+        rest = &rest[content_start + close + ticks..];
+    }
+    result.push_str(&strip_inline_syntax(rest, inline));
+    result
+}
+
+// The offset of the next run of exactly `ticks` backticks
+fn find_backtick_run(text: &str, ticks: usize) -> Option<usize> {
+    let mut offset = 0;
+    while let Some(start) = text[offset..].find('`') {
+        let start = offset + start;
+        let run = text[start..].chars().take_while(|c| *c == '`').count();
+        if run == ticks {
+            return Some(start);
+        }
+        offset = start + run;
+    }
+    None
+}
+
+fn strip_inline_syntax(text: &str, inline: &InlinePatterns) -> String {
+    let mut protected = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match chars.peek() {
+            Some(next) if c == '\\' && next.is_ascii_punctuation() => {
+                protected.extend(char::from_u32(ESCAPE_BASE + *next as u32));
+                chars.next();
+            }
+            _ => protected.push(c),
+        }
+    }
+
+    let text = inline.image.replace_all(&protected, "$1");
+    let text = inline
+        .link
+        .replace_all(&text, |captures: &regex::Captures| {
+            let (label, url) = (&captures[1], &captures[2]);
+            if label == url || url.starts_with('#') {
+                label.to_string()
+            } else {
+                format!("{} ({})", label, url)
+            }
+        });
+    let mut text = inline.autolink.replace_all(&text, "$1").into_owned();
+    // Repeated for nested emphasis and for neighbours that share a boundary
+    for _ in 0..3 {
+        let mut stripped = text.clone();
+        for (pattern, replacement) in &inline.emphasis {
+            stripped = pattern.replace_all(&stripped, *replacement).into_owned();
+        }
+        if stripped == text {
+            break;
+        }
+        text = stripped;
+    }
+
+    text.chars()
+        .map(|c| match c as u32 {
+            code if (ESCAPE_BASE..ESCAPE_BASE + 0x80).contains(&code) => {
+                char::from_u32(code - ESCAPE_BASE).unwrap_or(c)
+            }
+            _ => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip_markdown_removes_nested_emphasis() {
+        assert_eq!(strip_markdown("***both***"), "both");
+        assert_eq!(
+            strip_markdown("**bold _and italic_** and ~~gone~~"),
+            "bold and italic and gone"
+        );
+        assert_eq!(strip_markdown("*one* *two*"), "one two");
+    }
+
+    #[test]
+    fn strip_markdown_keeps_link_urls() {
+        assert_eq!(
+            strip_markdown("See [the docs](https://example.com/docs \"Docs\")."),
+            "See the docs (https://example.com/docs)."
+        );
+        assert_eq!(
+            strip_markdown("[https://example.com](https://example.com)"),
+            "https://example.com"
+        );
+        assert_eq!(strip_markdown("Jump to [usage](#usage)"), "Jump to usage");
+        assert_eq!(
+            strip_markdown("Mail <mailto:me@example.com>"),
+            "Mail mailto:me@example.com"
+        );
+        assert_eq!(strip_markdown("![A cat](cat.png)"), "A cat");
+    }
+
+    #[test]
+    fn strip_markdown_leaves_code_alone() {
+        assert_eq!(
+            strip_markdown("Pass `**kwargs` and ``a ` b``"),
+            "Pass **kwargs and a ` b"
+        );
+        assert_eq!(
+            strip_markdown("Before\n\n```rust\nlet x = *y*;\n\n\n# not a heading\n```\nAfter"),
+            "Before\n\nlet x = *y*;\n\n\n# not a heading\nAfter"
+        );
+    }
+
+    #[test]
+    fn strip_markdown_keeps_plain_text() {
+        for text in [
+            "2*3*4",
+            "a * b * c",
+            "snake_case_name and MAX_RETRY_COUNT",
+            "C:\\temp\\file_name.txt",
+            "5 > 3 is true",
+        ] {
+            assert_eq!(strip_markdown(text), text);
+        }
+        assert_eq!(strip_markdown("\\*not italic\\*"), "*not italic*");
+    }
+
+    #[test]
+    fn strip_markdown_flattens_blocks() {
+        assert_eq!(
+            strip_markdown("# Title #\n\n> quoted\n\n- one\n  - two\n3) three\n\n---"),
+            "Title\n\nquoted\n\n• one\n  • two\n3. three"
+        );
+        assert_eq!(
+            strip_markdown("| a | b \\| c |\n|---|:-:|\n| `x|y` | 2 |"),
+            "a\tb | c\nx|y\t2"
+        );
+    }
+
+    #[test]
+    fn tidy_text_cleans_whitespace_only() {
+        assert_eq!(
+            tidy_text("\r\n\n  first  \r\n\n\n\tsecond\t\n\n"),
+            "  first\n\n\tsecond"
+        );
+    }
+
+    #[test]
+    fn base64_and_url_round_trip() {
+        let text = "Zoë & co/?";
+        assert_eq!(base64_decode(&base64_encode(text).unwrap()).unwrap(), text);
+        assert_eq!(url_decode(&url_encode(text).unwrap()).unwrap(), text);
+        assert_eq!(base64_decode("Wm_DqyAmIGNvLz8").unwrap(), text);
+        assert!(base64_decode("  ").is_err());
+        assert!(url_decode("%FF").is_err());
+    }
+}
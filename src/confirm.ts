@@ -0,0 +1,30 @@
+import { invoke } from "@tauri-apps/api/tauri";
+import { listen } from "@tauri-apps/api/event";
+
+interface ConfirmState {
+  action: string;
+  chars: number;
+  estimated_tokens: number;
+  provider: string;
+}
+
+window.addEventListener("DOMContentLoaded", async () => {
+  const confirmMessage = document.getElementById("confirm-message") as HTMLParagraphElement;
+  const acceptButton = document.getElementById("accept-button") as HTMLButtonElement;
+  const cancelButton = document.getElementById("cancel-button") as HTMLButtonElement;
+
+  function showState(state: ConfirmState | null) {
+    acceptButton.disabled = !state;
+    confirmMessage.textContent = state
+      ? `The selection is ${state.chars.toLocaleString()} characters long (about ${state.estimated_tokens.toLocaleString()} tokens). Send it to ${state.provider} to ${state.action} it?`
+      : "Nothing is waiting to be sent.";
+  }
+
+  // A second large selection reuses this window
+  await listen<ConfirmState>("confirm:reset", (event) => showState(event.payload));
+
+  showState(await invoke<ConfirmState | null>("get_confirm_state"));
+
+  acceptButton.addEventListener("click", () => invoke("answer_confirm", { accepted: true }));
+  cancelButton.addEventListener("click", () => invoke("answer_confirm", { accepted: false }));
+});
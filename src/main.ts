@@ -1,66 +1,317 @@
 import { invoke } from "@tauri-apps/api/tauri";
+import { listen } from "@tauri-apps/api/event";
 
 interface Settings {
+  schema_version?: number;
   shortcut: string;
   translate_shortcut?: string;
   api_keys?: { [provider: string]: string };
   api_key?: string; // Legacy field for migration
   model: string;
-  base_url: string;
+  base_urls?: { [provider: string]: string };
+  base_url?: string; // Legacy field for migration
+  provider_models?: { [provider: string]: string };
+  fallback_providers?: string[];
+  action_overrides?: { [action: string]: { model?: string; temperature?: number; sound?: string } };
+  extra_headers?: { [provider: string]: { [header: string]: string } };
+  extra_body?: { [provider: string]: unknown };
+  notification_strings?: { [key: string]: string };
+  glossary?: { [term: string]: string };
   prompt: string;
   provider: string;
   sound_enabled?: boolean;
-  notifications_enabled?: boolean;
+  notify_on_success?: boolean;
+  notify_on_error?: boolean;
+  clipboard_format?: string;
+  append_to_clipboard?: boolean;
+  clipboard_separator?: string;
+  result_template?: string | null;
+  show_stats_in_notification?: boolean;
+  replace_mode?: boolean;
+  replace_method?: string;
+  typing_delay_ms?: number;
+  normalize_input?: boolean;
+  straighten_quotes?: boolean;
+  strip_preamble?: boolean;
+  few_shot_examples?: [string, string][];
+  structured_translation?: boolean;
+  top_p?: number | null;
+  proofread_shortcut?: string;
+  proofread_prompt?: string;
+  proofread_temperature?: number;
+  recall_shortcut?: string;
+  clipboard_polish_shortcut?: string;
+  regenerate_shortcut?: string;
+  settings_shortcut?: string;
+  retry_shortcut?: string;
+  max_tokens?: number;
+  model_max_tokens?: { [model: string]: number };
+  selection_capture_delay_ms?: number;
+  debounce_ms?: number;
+  request_timeout_secs?: number;
+  max_concurrent_requests?: number;
+  timeouts?: { [provider: string]: number };
+  min_selection_length?: number;
+  confirm_large_input_chars?: number;
+  max_result_chars?: number;
+  start_notification_delay_ms?: number;
+  history_max_entry_chars?: number;
+  notification_urgency?: string;
+  error_notification_urgency?: string;
+  notification_timeout_ms?: number | null;
+  target_languages?: string[];
+  cache_size?: number;
+  cache_ttl_secs?: number;
+  shortcut_bindings?: { shortcut: string; action: string; prompt?: string; model?: string; from_clipboard?: boolean }[];
+  daily_request_limit?: number | null;
+  wrap_width?: number | null;
+  post_processors?: string[];
+  commit_shortcut?: string;
+  code_polish_shortcut?: string;
+  auto_detect_code?: boolean;
+  tidy_shortcut?: string;
+  case_shortcut?: string;
+  strip_markdown_shortcut?: string;
+  commit_prompt?: string;
+  use_context?: boolean;
+  context?: string;
+  style_guide?: string;
+  redact_patterns?: string[];
+  tone?: string;
+  locale_override?: string;
+  watch_settings_file?: boolean;
+  verify_clipboard_write?: boolean;
+  preview_before_apply?: boolean;
+  stream?: boolean;
+  open_settings_on_auth_error?: boolean;
+  preserve_language_on_polish?: boolean;
+  debug_logging?: boolean;
+  persist_cache?: boolean;
+  linux_primary_selection?: boolean;
+  notify_on_start?: boolean;
+  save_history?: boolean;
+  history_store_full_text?: boolean;
+  terminal_integration?: boolean;
+  retry_on_language_change?: boolean;
+  verify_language_preserved?: boolean;
+  safe_mode?: boolean;
+  polish_after_translate?: boolean;
+  enforce_glossary?: boolean;
+  auto_close_settings_on_save?: boolean;
+  ax_direct_write?: boolean;
+  use_system_locale?: boolean;
+  show_tray_counter?: boolean;
+  show_result_on_copy_failure?: boolean;
+  keep_reviews?: boolean;
+  single_instance?: boolean;
+}
+
+interface TokenUsage {
+  input_tokens: number;
+  output_tokens: number;
+}
+
+interface ProviderResult {
+  provider: string;
+  model: string;
+  result: string | null;
+  error: string | null;
+  latency_ms: number;
+  usage: TokenUsage | null;
+}
+
+interface ProviderModel {
+  value: string;
+  label: string;
+}
+
+interface ProviderInfo {
+  id: string;
+  name: string;
+  requires_api_key: boolean;
+  default_base_url: string;
+  default_model: string;
+  models: ProviderModel[];
+}
+
+interface Capabilities {
+  temperature: boolean;
+  top_p: boolean;
+  json_output: boolean;
+  streaming: boolean;
+  max_tokens: number | null;
+}
+
+interface ErrorInfo {
+  timestamp: string;
+  action: string | null;
+  title: string;
+  message: string;
+}
+
+interface HealthIssue {
+  category: string;
+  message: string;
 }
 
 window.addEventListener("DOMContentLoaded", () => {
   const settingsForm = document.getElementById("settings-form");
   const shortcutInput = document.getElementById("shortcut") as HTMLInputElement;
   const translateShortcutInput = document.getElementById("translate-shortcut") as HTMLInputElement;
+  const targetLanguagesInput = document.getElementById("target-languages") as HTMLInputElement;
+  const proofreadShortcutInput = document.getElementById("proofread-shortcut") as HTMLInputElement;
+  const commitShortcutInput = document.getElementById("commit-shortcut") as HTMLInputElement;
+  const codePolishShortcutInput = document.getElementById("code-polish-shortcut") as HTMLInputElement;
+  const autoDetectCodeCheckbox = document.getElementById("auto-detect-code") as HTMLInputElement;
+  const tidyShortcutInput = document.getElementById("tidy-shortcut") as HTMLInputElement;
+  const caseShortcutInput = document.getElementById("case-shortcut") as HTMLInputElement;
+  const stripMarkdownShortcutInput = document.getElementById("strip-markdown-shortcut") as HTMLInputElement;
+  const recallShortcutInput = document.getElementById("recall-shortcut") as HTMLInputElement;
+  const clipboardPolishShortcutInput = document.getElementById("clipboard-polish-shortcut") as HTMLInputElement;
+  const regenerateShortcutInput = document.getElementById("regenerate-shortcut") as HTMLInputElement;
+  const settingsShortcutInput = document.getElementById("settings-shortcut") as HTMLInputElement;
+  const retryShortcutInput = document.getElementById("retry-shortcut") as HTMLInputElement;
   const apiKeyInput = document.getElementById("api-key") as HTMLInputElement;
   const providerSelect = document.getElementById("provider") as HTMLSelectElement;
   const modelSelect = document.getElementById("model") as HTMLSelectElement;
+  const fallbackProvidersInput = document.getElementById("fallback-providers") as HTMLInputElement;
+  const deleteApiKeyButton = document.getElementById("delete-api-key") as HTMLButtonElement;
   const baseUrlInput = document.getElementById("base-url") as HTMLInputElement;
   const promptTextarea = document.getElementById("prompt") as HTMLTextAreaElement;
+  const proofreadPromptTextarea = document.getElementById("proofread-prompt") as HTMLTextAreaElement;
+  const proofreadTemperatureInput = document.getElementById("proofread-temperature") as HTMLInputElement;
+  const commitPromptTextarea = document.getElementById("commit-prompt") as HTMLTextAreaElement;
+  const useContextCheckbox = document.getElementById("use-context") as HTMLInputElement;
+  const contextTextarea = document.getElementById("context") as HTMLTextAreaElement;
+  const styleGuideTextarea = document.getElementById("style-guide") as HTMLTextAreaElement;
+  const redactPatternsTextarea = document.getElementById("redact-patterns") as HTMLTextAreaElement;
+  const toneInput = document.getElementById("tone") as HTMLInputElement;
+  const localeOverrideInput = document.getElementById("locale-override") as HTMLInputElement;
   const soundEnabledCheckbox = document.getElementById("sound-enabled") as HTMLInputElement;
-  const notificationsEnabledCheckbox = document.getElementById("notifications-enabled") as HTMLInputElement;
+  const notifyOnSuccessCheckbox = document.getElementById("notify-on-success") as HTMLInputElement;
+  const notifyOnErrorCheckbox = document.getElementById("notify-on-error") as HTMLInputElement;
+  const clipboardFormatSelect = document.getElementById("clipboard-format") as HTMLSelectElement;
+  const appendToClipboardCheckbox = document.getElementById("append-to-clipboard") as HTMLInputElement;
+  const clipboardSeparatorSelect = document.getElementById("clipboard-separator") as HTMLSelectElement;
+  const resultTemplateTextarea = document.getElementById("result-template") as HTMLTextAreaElement;
+  const showStatsCheckbox = document.getElementById("show-stats") as HTMLInputElement;
+  const replaceModeCheckbox = document.getElementById("replace-mode") as HTMLInputElement;
+  const replaceMethodSelect = document.getElementById("replace-method") as HTMLSelectElement;
+  const typingDelayInput = document.getElementById("typing-delay") as HTMLInputElement;
+  const normalizeInputCheckbox = document.getElementById("normalize-input") as HTMLInputElement;
+  const straightenQuotesCheckbox = document.getElementById("straighten-quotes") as HTMLInputElement;
+  const stripPreambleCheckbox = document.getElementById("strip-preamble") as HTMLInputElement;
+  const fewShotList = document.getElementById("few-shot-list") as HTMLUListElement;
+  const fewShotInput = document.getElementById("few-shot-input") as HTMLTextAreaElement;
+  const fewShotOutput = document.getElementById("few-shot-output") as HTMLTextAreaElement;
+  const addFewShotButton = document.getElementById("add-few-shot") as HTMLButtonElement;
+  const structuredTranslationCheckbox = document.getElementById("structured-translation") as HTMLInputElement;
+  const structuredTranslationGroup = structuredTranslationCheckbox.closest(".checkbox-group") as HTMLDivElement;
+  const topPInput = document.getElementById("top-p") as HTMLInputElement;
+  const maxTokensInput = document.getElementById("max-tokens") as HTMLInputElement;
+  const topPGroup = document.getElementById("top-p-group") as HTMLDivElement;
+  const selectionDelayInput = document.getElementById("selection-delay") as HTMLInputElement;
+  const debounceInput = document.getElementById("debounce-ms") as HTMLInputElement;
+  const requestTimeoutInput = document.getElementById("request-timeout") as HTMLInputElement;
+  const maxConcurrentRequestsInput = document.getElementById("max-concurrent-requests") as HTMLInputElement;
+  const minSelectionLengthInput = document.getElementById("min-selection-length") as HTMLInputElement;
+  const confirmLargeInputInput = document.getElementById("confirm-large-input") as HTMLInputElement;
+  const maxResultCharsInput = document.getElementById("max-result-chars") as HTMLInputElement;
+  const startNotificationDelayInput = document.getElementById("start-notification-delay") as HTMLInputElement;
+  const historyMaxEntryCharsInput = document.getElementById("history-max-entry-chars") as HTMLInputElement;
+  const notificationUrgencySelect = document.getElementById("notification-urgency") as HTMLSelectElement;
+  const errorNotificationUrgencySelect = document.getElementById("error-notification-urgency") as HTMLSelectElement;
+  const notificationTimeoutInput = document.getElementById("notification-timeout") as HTMLInputElement;
+  const cacheSizeInput = document.getElementById("cache-size") as HTMLInputElement;
+  const cacheTtlInput = document.getElementById("cache-ttl") as HTMLInputElement;
+  const dailyLimitInput = document.getElementById("daily-limit") as HTMLInputElement;
+  const wrapWidthInput = document.getElementById("wrap-width") as HTMLInputElement;
+  const postProcessorsInput = document.getElementById("post-processors") as HTMLInputElement;
+  const todayCountSpan = document.getElementById("today-count") as HTMLSpanElement;
+  const watchSettingsFileCheckbox = document.getElementById("watch-settings-file") as HTMLInputElement;
+  const verifyClipboardWriteCheckbox = document.getElementById("verify-clipboard-write") as HTMLInputElement;
+  const settingsJsonTextarea = document.getElementById("settings-json") as HTMLTextAreaElement;
+  const exportIncludeKeysCheckbox = document.getElementById("export-include-keys") as HTMLInputElement;
+  const exportSettingsButton = document.getElementById("export-settings") as HTMLButtonElement;
+  const importSettingsButton = document.getElementById("import-settings") as HTMLButtonElement;
+  const compareTextArea = document.getElementById("compare-text") as HTMLTextAreaElement;
+  const compareModelsInput = document.getElementById("compare-models") as HTMLInputElement;
+  const compareTokenEstimate = document.getElementById("compare-token-estimate") as HTMLElement;
+  const compareButton = document.getElementById("compare-button") as HTMLButtonElement;
+  const compareTable = document.getElementById("compare-results") as HTMLTableElement;
+  const resetRuntimeStateButton = document.getElementById("reset-runtime-state") as HTMLButtonElement;
+  const previewBeforeApplyCheckbox = document.getElementById("preview-before-apply") as HTMLInputElement;
+  const streamCheckbox = document.getElementById("stream") as HTMLInputElement;
+  const streamGroup = streamCheckbox.closest(".checkbox-group") as HTMLDivElement;
+  const openSettingsOnAuthErrorCheckbox = document.getElementById("open-settings-on-auth-error") as HTMLInputElement;
+  const preserveLanguageCheckbox = document.getElementById("preserve-language-on-polish") as HTMLInputElement;
+  const debugLoggingCheckbox = document.getElementById("debug-logging") as HTMLInputElement;
+  const persistCacheCheckbox = document.getElementById("persist-cache") as HTMLInputElement;
+  const linuxPrimarySelectionCheckbox = document.getElementById("linux-primary-selection") as HTMLInputElement;
+  const notifyOnStartCheckbox = document.getElementById("notify-on-start") as HTMLInputElement;
+  const saveHistoryCheckbox = document.getElementById("save-history") as HTMLInputElement;
+  const historyStoreFullTextCheckbox = document.getElementById("history-store-full-text") as HTMLInputElement;
+  const terminalIntegrationCheckbox = document.getElementById("terminal-integration") as HTMLInputElement;
+  const retryOnLanguageChangeCheckbox = document.getElementById("retry-on-language-change") as HTMLInputElement;
+  const verifyLanguagePreservedCheckbox = document.getElementById("verify-language-preserved") as HTMLInputElement;
+  const safeModeCheckbox = document.getElementById("safe-mode") as HTMLInputElement;
+  const polishAfterTranslateCheckbox = document.getElementById("polish-after-translate") as HTMLInputElement;
+  const enforceGlossaryCheckbox = document.getElementById("enforce-glossary") as HTMLInputElement;
+  const autoCloseSettingsCheckbox = document.getElementById("auto-close-settings") as HTMLInputElement;
+  const axDirectWriteCheckbox = document.getElementById("ax-direct-write") as HTMLInputElement;
+  const useSystemLocaleCheckbox = document.getElementById("use-system-locale") as HTMLInputElement;
+  const showTrayCounterCheckbox = document.getElementById("show-tray-counter") as HTMLInputElement;
+  const showResultOnCopyFailureCheckbox = document.getElementById("show-result-on-copy-failure") as HTMLInputElement;
+  const keepReviewsCheckbox = document.getElementById("keep-reviews") as HTMLInputElement;
+  const singleInstanceCheckbox = document.getElementById("single-instance") as HTMLInputElement;
   const saveButton = document.getElementById("save-button") as HTMLButtonElement;
   const statusDiv = document.getElementById("status") as HTMLDivElement;
+  const healthDiv = document.getElementById("health") as HTMLDivElement;
+  const healthList = document.getElementById("health-list") as HTMLUListElement;
+  const lastErrorDiv = document.getElementById("last-error") as HTMLDivElement;
+  const lastErrorTitle = document.getElementById("last-error-title") as HTMLElement;
+  const lastErrorTime = document.getElementById("last-error-time") as HTMLElement;
+  const lastErrorMessage = document.getElementById("last-error-message") as HTMLParagraphElement;
+  const dismissErrorButton = document.getElementById("dismiss-error-button") as HTMLButtonElement;
 
   // Store API keys for each provider
   let providerApiKeys: { [provider: string]: string } = {};
 
-  // Provider-specific configurations
-  const providerConfigs = {
-    openai: {
-      models: [
-        { value: "gpt-3.5-turbo", label: "GPT-3.5 Turbo" },
-        { value: "gpt-4", label: "GPT-4" },
-        { value: "gpt-4-turbo", label: "GPT-4 Turbo" },
-        { value: "gpt-4o", label: "GPT-4o" }
-      ],
-      baseUrl: "https://api.openai.com/v1",
-      apiKeyPlaceholder: "Enter your OpenAI API key"
-    },
-    gemini: {
-      models: [
-        { value: "gemini-flash-latest", label: "Gemini Latest Flash" },
-        { value: "gemini-pro-latest", label: "Gemini Latest Pro" },
-        { value: "gemini-pro", label: "Gemini Pro" }
-      ],
-      baseUrl: "https://generativelanguage.googleapis.com",
-      apiKeyPlaceholder: "Enter your Google AI API key"
-    }
-  };
+  // Store base URLs for each provider
+  let providerBaseUrls: { [provider: string]: string } = {};
+  let providerModels: { [provider: string]: string } = {};
+
+  // Last settings loaded from the backend, so fields without a form control survive a save
+  let loadedSettings: Partial<Settings> = {};
+
+  // Provider-specific configurations, from the backend so a provider added
+  // there shows up here without frontend changes
+  const providerConfigs: { [provider: string]: ProviderInfo } = {};
+  const providersLoaded = invoke<ProviderInfo[]>("supported_providers").then((providers) => {
+    providers.forEach((provider) => {
+      providerConfigs[provider.id] = provider;
+      providerSelect.add(new Option(provider.name, provider.id));
+    });
+  });
+
+  function apiKeyPlaceholder(config: ProviderInfo) {
+    return config.requires_api_key ? `Enter your ${config.name} API key` : 'Not required';
+  }
 
   async function updateProviderUI() {
-    const provider = providerSelect.value as keyof typeof providerConfigs;
+    const provider = providerSelect.value;
     const config = providerConfigs[provider];
     
     // Save current API key before switching
     const currentProvider = Object.keys(providerConfigs).find(p => 
-      providerConfigs[p as keyof typeof providerConfigs].apiKeyPlaceholder === apiKeyInput.placeholder
+      apiKeyPlaceholder(providerConfigs[p]) === apiKeyInput.placeholder
     );
+    if (currentProvider && baseUrlInput.value) {
+      providerBaseUrls[currentProvider] = baseUrlInput.value;
+    }
+    if (currentProvider && modelSelect.value) {
+      providerModels[currentProvider] = modelSelect.value;
+    }
     if (currentProvider && apiKeyInput.value) {
       providerApiKeys[currentProvider] = apiKeyInput.value;
       await invoke("save_api_key_for_provider", { 
@@ -77,16 +328,26 @@ window.addEventListener("DOMContentLoaded", () => {
       option.textContent = model.label;
       modelSelect.appendChild(option);
     });
-    
-    // Update base URL if it's still default
-    if (baseUrlInput.value === providerConfigs.openai.baseUrl || 
-        baseUrlInput.value === providerConfigs.gemini.baseUrl || 
-        baseUrlInput.value === '') {
-      baseUrlInput.value = config.baseUrl;
+    if (providerModels[provider]) {
+      modelSelect.value = providerModels[provider];
     }
     
+    // Load base URL for new provider
+    baseUrlInput.value = providerBaseUrls[provider] || config.default_base_url;
+    
     // Update API key placeholder
-    apiKeyInput.placeholder = config.apiKeyPlaceholder;
+    apiKeyInput.placeholder = apiKeyPlaceholder(config);
+
+    // Hide settings the provider would ignore
+    try {
+      const capabilities = await invoke<Capabilities>("provider_capabilities", { provider });
+      topPGroup.hidden = !capabilities.top_p;
+      streamGroup.hidden = !capabilities.streaming;
+      structuredTranslationGroup.hidden = !capabilities.json_output;
+      maxTokensInput.max = capabilities.max_tokens != null ? String(capabilities.max_tokens) : '';
+    } catch (error) {
+      console.error("Failed to load provider capabilities:", error);
+    }
     
     // Load API key for new provider
     try {
@@ -98,11 +359,214 @@ window.addEventListener("DOMContentLoaded", () => {
     }
   }
 
+  function renderFewShotExamples(examples: [string, string][]) {
+    loadedSettings.few_shot_examples = examples;
+    fewShotList.innerHTML = '';
+    examples.forEach(([input, output], index) => {
+      const item = document.createElement('li');
+      item.className = 'few-shot-item';
+      const text = document.createElement('span');
+      text.textContent = `${input} → ${output}`;
+      const removeButton = document.createElement('button');
+      removeButton.type = 'button';
+      removeButton.className = 'remove-button';
+      removeButton.textContent = 'Remove';
+      removeButton.addEventListener('click', async () => {
+        try {
+          renderFewShotExamples(await invoke<[string, string][]>("remove_few_shot_example", { index }));
+        } catch (error) {
+          showStatus(`Failed to remove example: ${error}`, true);
+        }
+      });
+      item.appendChild(text);
+      item.appendChild(removeButton);
+      fewShotList.appendChild(item);
+    });
+  }
+
+  addFewShotButton.addEventListener('click', async () => {
+    try {
+      renderFewShotExamples(await invoke<[string, string][]>("add_few_shot_example", {
+        input: fewShotInput.value,
+        output: fewShotOutput.value
+      }));
+      fewShotInput.value = '';
+      fewShotOutput.value = '';
+    } catch (error) {
+      showStatus(`Failed to add example: ${error}`, true);
+    }
+  });
+
+  exportSettingsButton.addEventListener('click', async () => {
+    try {
+      settingsJsonTextarea.value = await invoke<string>("export_settings", {
+        includeApiKeys: exportIncludeKeysCheckbox.checked
+      });
+      showStatus("Settings exported");
+    } catch (error) {
+      showStatus(`Failed to export settings: ${error}`, true);
+    }
+  });
+
+  importSettingsButton.addEventListener('click', async () => {
+    try {
+      // The backend emits settings:reloaded, which re-populates the form
+      await invoke("import_settings", { json: settingsJsonTextarea.value });
+      showStatus("Settings imported");
+    } catch (error) {
+      showStatus(`Failed to import settings: ${error}`, true);
+    }
+  });
+
+  resetRuntimeStateButton.addEventListener('click', async () => {
+    try {
+      const cleared = await invoke<string[]>("reset_runtime_state");
+      showStatus(`Cleared ${cleared.join(', ')}`);
+    } catch (error) {
+      showStatus(`Failed to reset: ${error}`, true);
+    }
+  });
+
+  // An edit is about as long as its input, so an input over max_tokens will be cut off
+  compareTextArea.addEventListener('input', async () => {
+    if (compareTextArea.value.trim() === '') {
+      compareTokenEstimate.textContent = '';
+      return;
+    }
+    const tokens = await invoke<number>("estimate_tokens", {
+      text: compareTextArea.value,
+      model: modelSelect.value
+    });
+    const maxTokens = parseInt(maxTokensInput.value) || 1000;
+    compareTokenEstimate.textContent = tokens > maxTokens
+      ? `About ${tokens} tokens, more than the max tokens limit of ${maxTokens}, so results may be cut off`
+      : `About ${tokens} tokens`;
+  });
+
+  compareButton.addEventListener('click', async () => {
+    // "provider:model" pairs; a missing model uses the provider's default
+    const providers = compareModelsInput.value
+      .split(',')
+      .map((entry) => entry.trim())
+      .filter((entry) => entry.length > 0)
+      .map((entry) => {
+        const [provider, ...model] = entry.split(':');
+        return { provider: provider.trim(), model: model.join(':').trim() };
+      });
+    if (providers.length === 0) {
+      showStatus("Enter at least one provider:model pair", true);
+      return;
+    }
+
+    compareButton.disabled = true;
+    try {
+      const results = await invoke<ProviderResult[]>("compare_providers", {
+        text: compareTextArea.value,
+        providers
+      });
+      const body = compareTable.tBodies[0];
+      body.innerHTML = '';
+      results.forEach((entry) => {
+        const row = body.insertRow();
+        row.insertCell().textContent = `${entry.provider}:${entry.model}`;
+        const resultCell = row.insertCell();
+        resultCell.textContent = entry.result ?? entry.error ?? '';
+        if (entry.error) {
+          resultCell.className = 'compare-error';
+        }
+        row.insertCell().textContent = `${(entry.latency_ms / 1000).toFixed(1)}s`;
+        row.insertCell().textContent = entry.usage
+          ? `${entry.usage.input_tokens} / ${entry.usage.output_tokens}`
+          : '-';
+      });
+      compareTable.style.display = 'table';
+    } catch (error) {
+      showStatus(`Failed to compare models: ${error}`, true);
+    } finally {
+      compareButton.disabled = false;
+    }
+  });
+
+  async function refreshHealth() {
+    const issues = await invoke<HealthIssue[]>("get_health");
+    healthList.innerHTML = '';
+    issues.forEach((issue) => {
+      const item = document.createElement('li');
+      item.textContent = issue.message;
+      healthList.appendChild(item);
+    });
+    healthDiv.style.display = issues.length > 0 ? 'block' : 'none';
+  }
+
+  refreshHealth();
+
+  function showLastError(error: ErrorInfo | null) {
+    if (error) {
+      lastErrorTitle.textContent = error.title;
+      lastErrorTime.textContent = new Date(error.timestamp).toLocaleString();
+      lastErrorMessage.textContent = error.message;
+    }
+    lastErrorDiv.style.display = error ? 'block' : 'none';
+  }
+
+  invoke<ErrorInfo | null>("get_last_error").then(showLastError);
+  listen<ErrorInfo>("error:new", (event) => showLastError(event.payload));
+
+  dismissErrorButton.addEventListener('click', async () => {
+    await invoke("clear_last_error");
+    showLastError(null);
+  });
+
+  // Mark providers that have a key in the provider list
+  async function refreshConfiguredProviders() {
+    const configured = await invoke<string[]>("list_configured_providers");
+    Array.from(providerSelect.options).forEach((option) => {
+      option.dataset.label ??= option.textContent || '';
+      option.textContent = configured.includes(option.value)
+        ? `${option.dataset.label} ✓`
+        : option.dataset.label;
+    });
+  }
+
+  providersLoaded.then(refreshConfiguredProviders);
+
+  deleteApiKeyButton.addEventListener('click', async () => {
+    const provider = providerSelect.value;
+    try {
+      await invoke("delete_api_key_for_provider", { provider });
+      delete providerApiKeys[provider];
+      apiKeyInput.value = '';
+      await refreshConfiguredProviders();
+      await refreshHealth();
+      showStatus("API key deleted");
+    } catch (error) {
+      showStatus(`Failed to delete API key: ${error}`, true);
+    }
+  });
+
+  invoke<number>("get_today_count").then((count) => {
+    todayCountSpan.textContent = String(count);
+  });
+
   // Load settings when the window opens
   invoke<Settings>("load_settings").then(async (settings) => {
+    await providersLoaded;
     if (settings) {
+      loadedSettings = settings;
       shortcutInput.value = settings.shortcut;
       translateShortcutInput.value = settings.translate_shortcut || 'CmdOrCtrl+Alt+T';
+      targetLanguagesInput.value = (settings.target_languages || []).join(', ');
+      proofreadShortcutInput.value = settings.proofread_shortcut || 'CmdOrCtrl+Alt+R';
+      commitShortcutInput.value = settings.commit_shortcut ?? 'CmdOrCtrl+Alt+G';
+      codePolishShortcutInput.value = settings.code_polish_shortcut || '';
+      tidyShortcutInput.value = settings.tidy_shortcut || '';
+      caseShortcutInput.value = settings.case_shortcut || '';
+      stripMarkdownShortcutInput.value = settings.strip_markdown_shortcut || '';
+      recallShortcutInput.value = settings.recall_shortcut || '';
+      clipboardPolishShortcutInput.value = settings.clipboard_polish_shortcut || '';
+      regenerateShortcutInput.value = settings.regenerate_shortcut || '';
+      settingsShortcutInput.value = settings.settings_shortcut || '';
+      retryShortcutInput.value = settings.retry_shortcut || '';
       providerSelect.value = settings.provider || 'openai';
       
       // Load API keys for all providers
@@ -114,20 +578,150 @@ window.addEventListener("DOMContentLoaded", () => {
         providerApiKeys[settings.provider] = settings.api_key;
       }
       
+      providerBaseUrls = { ...settings.base_urls };
+      providerModels = { ...settings.provider_models };
+      fallbackProvidersInput.value = (settings.fallback_providers || []).join(', ');
+      
       await updateProviderUI();
+      renderFewShotExamples(settings.few_shot_examples || []);
       modelSelect.value = settings.model;
-      baseUrlInput.value = settings.base_url;
       promptTextarea.value = settings.prompt;
+      proofreadPromptTextarea.value = settings.proofread_prompt || '';
+      proofreadTemperatureInput.value = String(settings.proofread_temperature ?? 0.1);
+      commitPromptTextarea.value = settings.commit_prompt || '';
+      useContextCheckbox.checked = settings.use_context === true; // Default to false
+      contextTextarea.value = settings.context || '';
+      styleGuideTextarea.value = settings.style_guide || '';
+      redactPatternsTextarea.value = (settings.redact_patterns || []).join('\n');
+      toneInput.value = settings.tone || 'neutral';
+      localeOverrideInput.value = settings.locale_override || '';
       soundEnabledCheckbox.checked = settings.sound_enabled !== false; // Default to true
-      notificationsEnabledCheckbox.checked = settings.notifications_enabled === true; // Default to false
+      notifyOnSuccessCheckbox.checked = settings.notify_on_success === true; // Default to false
+      notifyOnErrorCheckbox.checked = settings.notify_on_error !== false; // Default to true
+      clipboardFormatSelect.value = settings.clipboard_format || 'ResultOnly';
+      appendToClipboardCheckbox.checked = settings.append_to_clipboard === true; // Default to false
+      const separator = settings.clipboard_separator ?? '\n\n';
+      // Keep a separator set in settings.json even if it isn't one of the presets
+      if (!Array.from(clipboardSeparatorSelect.options).some(option => option.value === separator)) {
+        clipboardSeparatorSelect.add(new Option('Custom (from settings.json)', separator));
+      }
+      clipboardSeparatorSelect.value = separator;
+      resultTemplateTextarea.value = settings.result_template || '';
+      showStatsCheckbox.checked = settings.show_stats_in_notification === true; // Default to false
+      replaceModeCheckbox.checked = settings.replace_mode === true; // Default to false
+      replaceMethodSelect.value = settings.replace_method || 'Type';
+      typingDelayInput.value = String(settings.typing_delay_ms ?? 0);
+      normalizeInputCheckbox.checked = settings.normalize_input === true; // Default to false
+      straightenQuotesCheckbox.checked = settings.straighten_quotes === true; // Default to false
+      stripPreambleCheckbox.checked = settings.strip_preamble !== false; // Default to true
+      structuredTranslationCheckbox.checked = settings.structured_translation === true; // Default to false
+      topPInput.value = settings.top_p != null ? String(settings.top_p) : '';
+      maxTokensInput.value = String(settings.max_tokens ?? 1000);
+      selectionDelayInput.value = String(settings.selection_capture_delay_ms ?? 0);
+      debounceInput.value = String(settings.debounce_ms ?? 300);
+      requestTimeoutInput.value = String(settings.request_timeout_secs ?? 60);
+      maxConcurrentRequestsInput.value = String(settings.max_concurrent_requests ?? 2);
+      minSelectionLengthInput.value = String(settings.min_selection_length ?? 0);
+      confirmLargeInputInput.value = String(settings.confirm_large_input_chars ?? 20000);
+      maxResultCharsInput.value = String(settings.max_result_chars ?? 100000);
+      startNotificationDelayInput.value = String(settings.start_notification_delay_ms ?? 1500);
+      historyMaxEntryCharsInput.value = String(settings.history_max_entry_chars ?? 2000);
+      notificationUrgencySelect.value = settings.notification_urgency || 'Normal';
+      errorNotificationUrgencySelect.value = settings.error_notification_urgency || 'Critical';
+      notificationTimeoutInput.value = settings.notification_timeout_ms != null ? String(settings.notification_timeout_ms / 1000) : '';
+      cacheSizeInput.value = String(settings.cache_size ?? 0);
+      cacheTtlInput.value = String(Math.round((settings.cache_ttl_secs ?? 86400) / 60));
+      dailyLimitInput.value = settings.daily_request_limit != null ? String(settings.daily_request_limit) : '';
+      wrapWidthInput.value = settings.wrap_width != null ? String(settings.wrap_width) : '';
+      postProcessorsInput.value = (settings.post_processors || []).join(', ');
+      watchSettingsFileCheckbox.checked = settings.watch_settings_file === true; // Default to false
+      verifyClipboardWriteCheckbox.checked = settings.verify_clipboard_write === true; // Default to false
+      previewBeforeApplyCheckbox.checked = settings.preview_before_apply === true; // Default to false
+      streamCheckbox.checked = settings.stream === true; // Default to false
+      openSettingsOnAuthErrorCheckbox.checked = settings.open_settings_on_auth_error !== false; // Default to true
+      preserveLanguageCheckbox.checked = settings.preserve_language_on_polish !== false; // Default to true
+      debugLoggingCheckbox.checked = settings.debug_logging === true; // Default to false
+      persistCacheCheckbox.checked = settings.persist_cache === true; // Default to false
+      linuxPrimarySelectionCheckbox.checked = settings.linux_primary_selection === true; // Default to false
+      notifyOnStartCheckbox.checked = settings.notify_on_start === true; // Default to false
+      saveHistoryCheckbox.checked = settings.save_history === true; // Default to false
+      historyStoreFullTextCheckbox.checked = settings.history_store_full_text === true; // Default to false
+      terminalIntegrationCheckbox.checked = settings.terminal_integration === true; // Default to false
+      retryOnLanguageChangeCheckbox.checked = settings.retry_on_language_change === true; // Default to false
+      verifyLanguagePreservedCheckbox.checked = settings.verify_language_preserved === true; // Default to false
+      safeModeCheckbox.checked = settings.safe_mode === true; // Default to false
+      autoDetectCodeCheckbox.checked = settings.auto_detect_code === true; // Default to false
+      polishAfterTranslateCheckbox.checked = settings.polish_after_translate === true; // Default to false
+      enforceGlossaryCheckbox.checked = settings.enforce_glossary === true; // Default to false
+      autoCloseSettingsCheckbox.checked = settings.auto_close_settings_on_save === true; // Default to false
+      axDirectWriteCheckbox.checked = settings.ax_direct_write === true; // Default to false
+      useSystemLocaleCheckbox.checked = settings.use_system_locale === true; // Default to false
+      showTrayCounterCheckbox.checked = settings.show_tray_counter === true; // Default to false
+      showResultOnCopyFailureCheckbox.checked = settings.show_result_on_copy_failure !== false; // Default to true
+      keepReviewsCheckbox.checked = settings.keep_reviews === true; // Default to false
+      singleInstanceCheckbox.checked = settings.single_instance !== false; // Default to true
     } else {
       await updateProviderUI();
       // Set defaults for new installations
+      postProcessorsInput.value = 'unmask_redactions, strip_preamble, wrap, template';
+      startNotificationDelayInput.value = '1500';
+      historyMaxEntryCharsInput.value = '2000';
+      confirmLargeInputInput.value = '20000';
+      maxResultCharsInput.value = '100000';
+      debounceInput.value = '300';
+      requestTimeoutInput.value = '60';
+      maxConcurrentRequestsInput.value = '2';
+      notificationUrgencySelect.value = 'Normal';
+      errorNotificationUrgencySelect.value = 'Critical';
+      toneInput.value = 'neutral';
       soundEnabledCheckbox.checked = true;
-      notificationsEnabledCheckbox.checked = false;
+      notifyOnSuccessCheckbox.checked = false;
+      notifyOnErrorCheckbox.checked = true;
+      clipboardFormatSelect.value = 'ResultOnly';
+      appendToClipboardCheckbox.checked = false;
+      clipboardSeparatorSelect.value = '\n\n';
+      resultTemplateTextarea.value = '';
+      showStatsCheckbox.checked = false;
+      replaceModeCheckbox.checked = false;
+      replaceMethodSelect.value = 'Type';
+      typingDelayInput.value = '0';
+      normalizeInputCheckbox.checked = false;
+      straightenQuotesCheckbox.checked = false;
+      stripPreambleCheckbox.checked = true;
+      structuredTranslationCheckbox.checked = false;
+      proofreadTemperatureInput.value = '0.1';
+      watchSettingsFileCheckbox.checked = false;
+      verifyClipboardWriteCheckbox.checked = false;
+      previewBeforeApplyCheckbox.checked = false;
+      streamCheckbox.checked = false;
+      openSettingsOnAuthErrorCheckbox.checked = true;
+      preserveLanguageCheckbox.checked = true;
+      debugLoggingCheckbox.checked = false;
+      persistCacheCheckbox.checked = false;
+      linuxPrimarySelectionCheckbox.checked = false;
+      notifyOnStartCheckbox.checked = false;
+      saveHistoryCheckbox.checked = false;
+      historyStoreFullTextCheckbox.checked = false;
+      terminalIntegrationCheckbox.checked = false;
+      retryOnLanguageChangeCheckbox.checked = false;
+      verifyLanguagePreservedCheckbox.checked = false;
+      safeModeCheckbox.checked = false;
+      autoDetectCodeCheckbox.checked = false;
+      polishAfterTranslateCheckbox.checked = false;
+      enforceGlossaryCheckbox.checked = false;
+      autoCloseSettingsCheckbox.checked = false;
+      axDirectWriteCheckbox.checked = false;
+      useSystemLocaleCheckbox.checked = false;
+      showTrayCounterCheckbox.checked = false;
+      showResultOnCopyFailureCheckbox.checked = true;
+      keepReviewsCheckbox.checked = false;
+      singleInstanceCheckbox.checked = true;
     }
   });
 
+  // Re-populate the form when settings.json is reloaded from disk, so a save doesn't overwrite the edits
+  listen("settings:reloaded", () => window.location.reload());
+
   // Update UI when provider changes
   providerSelect.addEventListener('change', updateProviderUI);
 
@@ -156,20 +750,123 @@ window.addEventListener("DOMContentLoaded", () => {
         });
       }
 
+      const topP = parseFloat(topPInput.value);
+      const proofreadTemperature = parseFloat(proofreadTemperatureInput.value);
+      const maxTokens = parseInt(maxTokensInput.value, 10);
+      const selectionDelay = parseInt(selectionDelayInput.value, 10);
+      const debounce = parseInt(debounceInput.value, 10);
+      const requestTimeout = parseInt(requestTimeoutInput.value, 10);
+      const maxConcurrentRequests = parseInt(maxConcurrentRequestsInput.value, 10);
+      const minSelectionLength = parseInt(minSelectionLengthInput.value, 10);
+      const confirmLargeInput = parseInt(confirmLargeInputInput.value, 10);
+      const maxResultChars = parseInt(maxResultCharsInput.value, 10);
+      const startNotificationDelay = parseInt(startNotificationDelayInput.value, 10);
+      const historyMaxEntryChars = parseInt(historyMaxEntryCharsInput.value, 10);
+      const notificationTimeout = parseInt(notificationTimeoutInput.value, 10);
+      const cacheSize = parseInt(cacheSizeInput.value, 10);
+      const cacheTtlMinutes = parseInt(cacheTtlInput.value, 10);
+      const typingDelay = parseInt(typingDelayInput.value, 10);
+      const dailyLimit = parseInt(dailyLimitInput.value, 10);
+      const wrapWidth = parseInt(wrapWidthInput.value, 10);
+
       const settings: Settings = {
+        ...loadedSettings,
         shortcut: shortcutInput.value,
         translate_shortcut: translateShortcutInput.value,
+        target_languages: targetLanguagesInput.value.split(',').map(language => language.trim()).filter(language => language !== ''),
+        proofread_shortcut: proofreadShortcutInput.value,
+        commit_shortcut: commitShortcutInput.value,
+        code_polish_shortcut: codePolishShortcutInput.value,
+        tidy_shortcut: tidyShortcutInput.value,
+        case_shortcut: caseShortcutInput.value,
+        strip_markdown_shortcut: stripMarkdownShortcutInput.value,
+        recall_shortcut: recallShortcutInput.value,
+        clipboard_polish_shortcut: clipboardPolishShortcutInput.value,
+        regenerate_shortcut: regenerateShortcutInput.value,
+        settings_shortcut: settingsShortcutInput.value,
+        retry_shortcut: retryShortcutInput.value,
         api_keys: providerApiKeys,
         provider: providerSelect.value,
         model: modelSelect.value,
-        base_url: baseUrlInput.value,
+        base_urls: { ...providerBaseUrls, [currentProvider]: baseUrlInput.value },
+        provider_models: { ...providerModels, [currentProvider]: modelSelect.value },
+        fallback_providers: fallbackProvidersInput.value.split(',').map(provider => provider.trim().toLowerCase()).filter(provider => provider !== ''),
         prompt: promptTextarea.value,
+        proofread_prompt: proofreadPromptTextarea.value,
+        proofread_temperature: isNaN(proofreadTemperature) ? 0.1 : proofreadTemperature,
+        commit_prompt: commitPromptTextarea.value,
+        use_context: useContextCheckbox.checked,
+        context: contextTextarea.value,
+        style_guide: styleGuideTextarea.value,
+        redact_patterns: redactPatternsTextarea.value.split('\n').map(pattern => pattern.trim()).filter(pattern => pattern !== ''),
+        tone: toneInput.value.trim() || 'neutral',
+        locale_override: localeOverrideInput.value.trim(),
         sound_enabled: soundEnabledCheckbox.checked,
-        notifications_enabled: notificationsEnabledCheckbox.checked,
+        notify_on_success: notifyOnSuccessCheckbox.checked,
+        notify_on_error: notifyOnErrorCheckbox.checked,
+        clipboard_format: clipboardFormatSelect.value,
+        append_to_clipboard: appendToClipboardCheckbox.checked,
+        clipboard_separator: clipboardSeparatorSelect.value,
+        result_template: resultTemplateTextarea.value.trim() === '' ? null : resultTemplateTextarea.value,
+        show_stats_in_notification: showStatsCheckbox.checked,
+        replace_mode: replaceModeCheckbox.checked,
+        replace_method: replaceMethodSelect.value,
+        typing_delay_ms: isNaN(typingDelay) || typingDelay < 0 ? 0 : typingDelay,
+        normalize_input: normalizeInputCheckbox.checked,
+        straighten_quotes: straightenQuotesCheckbox.checked,
+        strip_preamble: stripPreambleCheckbox.checked,
+        structured_translation: structuredTranslationCheckbox.checked,
+        top_p: isNaN(topP) ? null : topP,
+        max_tokens: isNaN(maxTokens) || maxTokens < 1 ? 1000 : maxTokens,
+        selection_capture_delay_ms: isNaN(selectionDelay) || selectionDelay < 0 ? 0 : selectionDelay,
+        debounce_ms: isNaN(debounce) || debounce < 0 ? 300 : debounce,
+        request_timeout_secs: isNaN(requestTimeout) || requestTimeout < 0 ? 60 : requestTimeout,
+        max_concurrent_requests: isNaN(maxConcurrentRequests) || maxConcurrentRequests < 1 ? 2 : maxConcurrentRequests,
+        min_selection_length: isNaN(minSelectionLength) || minSelectionLength < 0 ? 0 : minSelectionLength,
+        confirm_large_input_chars: isNaN(confirmLargeInput) || confirmLargeInput < 0 ? 20000 : confirmLargeInput,
+        max_result_chars: isNaN(maxResultChars) || maxResultChars < 0 ? 100000 : maxResultChars,
+        start_notification_delay_ms: isNaN(startNotificationDelay) || startNotificationDelay < 0 ? 1500 : startNotificationDelay,
+        history_max_entry_chars: isNaN(historyMaxEntryChars) || historyMaxEntryChars < 1 ? 2000 : historyMaxEntryChars,
+        notification_urgency: notificationUrgencySelect.value,
+        error_notification_urgency: errorNotificationUrgencySelect.value,
+        notification_timeout_ms: isNaN(notificationTimeout) || notificationTimeout < 0 ? null : notificationTimeout * 1000,
+        cache_size: isNaN(cacheSize) || cacheSize < 0 ? 0 : cacheSize,
+        cache_ttl_secs: isNaN(cacheTtlMinutes) || cacheTtlMinutes < 1 ? 86400 : cacheTtlMinutes * 60,
+        daily_request_limit: isNaN(dailyLimit) || dailyLimit < 0 ? null : dailyLimit,
+        wrap_width: isNaN(wrapWidth) || wrapWidth < 1 ? null : wrapWidth,
+        post_processors: postProcessorsInput.value.split(',').map(step => step.trim().toLowerCase()).filter(step => step !== ''),
+        watch_settings_file: watchSettingsFileCheckbox.checked,
+        verify_clipboard_write: verifyClipboardWriteCheckbox.checked,
+        preview_before_apply: previewBeforeApplyCheckbox.checked,
+        stream: streamCheckbox.checked,
+        open_settings_on_auth_error: openSettingsOnAuthErrorCheckbox.checked,
+        preserve_language_on_polish: preserveLanguageCheckbox.checked,
+        debug_logging: debugLoggingCheckbox.checked,
+        persist_cache: persistCacheCheckbox.checked,
+        linux_primary_selection: linuxPrimarySelectionCheckbox.checked,
+        notify_on_start: notifyOnStartCheckbox.checked,
+        save_history: saveHistoryCheckbox.checked,
+        history_store_full_text: historyStoreFullTextCheckbox.checked,
+        terminal_integration: terminalIntegrationCheckbox.checked,
+        retry_on_language_change: retryOnLanguageChangeCheckbox.checked,
+        verify_language_preserved: verifyLanguagePreservedCheckbox.checked,
+        safe_mode: safeModeCheckbox.checked,
+        auto_detect_code: autoDetectCodeCheckbox.checked,
+        polish_after_translate: polishAfterTranslateCheckbox.checked,
+        enforce_glossary: enforceGlossaryCheckbox.checked,
+        auto_close_settings_on_save: autoCloseSettingsCheckbox.checked,
+        ax_direct_write: axDirectWriteCheckbox.checked,
+        use_system_locale: useSystemLocaleCheckbox.checked,
+        show_tray_counter: showTrayCounterCheckbox.checked,
+        show_result_on_copy_failure: showResultOnCopyFailureCheckbox.checked,
+        keep_reviews: keepReviewsCheckbox.checked,
+        single_instance: singleInstanceCheckbox.checked,
       };
 
       await invoke("save_settings", { settings });
       showStatus("Settings saved successfully!");
+      await refreshHealth();
+      await refreshConfiguredProviders();
     } catch (error) {
       showStatus(`Failed to save settings: ${error}`, true);
     } finally {
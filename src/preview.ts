@@ -0,0 +1,56 @@
+import { invoke } from "@tauri-apps/api/tauri";
+import { listen } from "@tauri-apps/api/event";
+import { appWindow } from "@tauri-apps/api/window";
+
+interface PreviewState {
+  text: string;
+  done: boolean;
+  error?: string | null;
+  safe_mode?: boolean;
+}
+
+window.addEventListener("DOMContentLoaded", async () => {
+  const previewText = document.getElementById("preview-text") as HTMLTextAreaElement;
+  const previewStatus = document.getElementById("preview-status") as HTMLElement;
+  const acceptButton = document.getElementById("accept-button") as HTMLButtonElement;
+  const cancelButton = document.getElementById("cancel-button") as HTMLButtonElement;
+  const statusDiv = document.getElementById("status") as HTMLDivElement;
+
+  function showState(state: PreviewState) {
+    previewText.value = state.text;
+    previewText.readOnly = !state.done || !!state.error;
+    acceptButton.disabled = !state.done || !!state.error;
+    acceptButton.textContent = state.safe_mode ? "Copy" : "Accept";
+    if (state.error) {
+      previewStatus.textContent = `Failed: ${state.error}`;
+    } else if (state.done && state.safe_mode) {
+      previewStatus.textContent = "Safe mode is on. Nothing has been copied; press Copy to copy this text to the clipboard";
+    } else if (state.done) {
+      previewStatus.textContent = "Edit the text if needed, then accept to copy it to the clipboard";
+    } else {
+      previewStatus.textContent = "Waiting for the response...";
+    }
+  }
+
+  // Listen first so no chunk is missed between fetching the state and subscribing
+  await listen<string>("polish:chunk", (event) => {
+    previewText.value += event.payload;
+    previewText.scrollTop = previewText.scrollHeight;
+  });
+  await listen<PreviewState>("polish:done", (event) => showState(event.payload));
+  await listen("polish:reset", () => showState({ text: "", done: false }));
+
+  showState(await invoke<PreviewState>("get_preview_state"));
+
+  acceptButton.addEventListener("click", async () => {
+    try {
+      await invoke("accept_preview", { text: previewText.value });
+    } catch (error) {
+      statusDiv.textContent = `Failed to copy: ${error}`;
+      statusDiv.className = "status error";
+      statusDiv.style.display = "block";
+    }
+  });
+
+  cancelButton.addEventListener("click", () => appWindow.close());
+});
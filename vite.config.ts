@@ -18,5 +18,12 @@ export default defineConfig(async () => ({
   // Ensure build outputs to dist directory
   build: {
     outDir: "dist",
+    rollupOptions: {
+      input: {
+        main: "index.html",
+        preview: "preview.html",
+        confirm: "confirm.html",
+      },
+    },
   },
 }));